@@ -1,11 +1,14 @@
 //! Main traits are here.
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
 
-use anyhow::Result;
 use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use serde::de::DeserializeOwned;
 
-use crate::query::{Query, F};
+use crate::error::Result;
+use crate::query::{Agg, Cursor, Query, F};
 
 /// Trait that must be implemented for a repository.
 #[async_trait]
@@ -15,30 +18,258 @@ pub trait Repo<T> {
     /// Finds an entity and returns it.
     /// Returns `None` if the entity is missing.
     async fn get<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<Option<T>>;
+    /// Finds an entity that must be the only one matching a given filter.
+    /// Returns [Error::NotFound](crate::Error::NotFound) if the entity is missing
+    /// and [Error::MultipleRows](crate::Error::MultipleRows) if several entities match the filter.
+    async fn get_one<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<T>;
     /// Finds and returns several entities.
     async fn get_many<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Vec<T>>;
+    /// Finds entities and loads only given fields of them.
+    /// Each entity is deserialized to `R`, which can be a type with these fields,
+    /// `serde_json::Value` or `HashMap<String, serde_json::Value>`.
+    async fn project<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        query: &Query,
+        fields: &[&str],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send;
+    /// Finds a page of entities together with the total number
+    /// of entities matching the [Query::filter].
+    /// The page contains at most [Query::limit] entities that follow the [Query::after] cursor.
+    /// If the [Query::order] is set, the page also contains
+    /// a cursor of the next page if there are more entities.
+    async fn get_page<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Page<T>>;
+    /// Finds several entities and returns them as a stream,
+    /// so they don't have to be loaded into memory at once.
+    /// A transaction can't execute other queries until the stream is dropped.
+    fn stream<'a, 'b>(&'b self, db: &'b Self::Db<'a>, query: &'b Query)
+        -> BoxStream<'b, Result<T>>;
     /// Adds a new entry to the repository.
     async fn add<'a>(&self, db: &Self::Db<'a>, entity: &T) -> Result<()>;
-    /// Saves an updated entity.
-    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()>;
+    /// Adds several new entries to the repository.
+    async fn add_many<'a>(&self, db: &Self::Db<'a>, entities: &[T]) -> Result<()>;
+    /// Adds a new entity or updates an existing one
+    /// if it has the same values of the `conflict_target` fields.
+    /// Null values never match, like in unique indexes of PostgreSQL.
+    async fn upsert<'a>(
+        &self,
+        db: &Self::Db<'a>,
+        entity: &T,
+        conflict_target: &[&str],
+    ) -> Result<Upserted>;
+    /// Saves an updated entity. Returns the number of updated records.
+    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<u64>;
+    /// Saves an updated entity if exactly one record matches a given filter.
+    /// Returns [Error::NotFound](crate::Error::NotFound) if nothing matches the filter
+    /// and [Error::MultipleRows](crate::Error::MultipleRows)
+    /// without updating anything if several records match it.
+    async fn update_one<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()>;
     /// Deletes entities matching a given filter.
-    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<()>;
+    /// Returns the number of deleted records.
+    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<u64>;
     /// Checks if there is an entity matching a given filter.
     async fn exists<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<bool>;
     /// Counts entities matching a given filter.
     async fn count<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<i64>;
     /// Counts entities in the repository.
     async fn count_all<'a>(&self, db: &Self::Db<'a>) -> Result<i64>;
+    /// Calculates aggregate functions over entities matching a given filter
+    /// (or all entities if it's `None`) and groups the results by given fields.
+    /// Each result row contains the grouping fields and the functions
+    /// named according to [Agg::alias]. Rows are sorted by the grouping fields
+    /// with null groups first.
+    async fn aggregate<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        filter: Option<&F>,
+        group_by: &[&str],
+        aggs: &[Agg],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send;
     /// Finds an entity and locks it for update. Returns `None` if the entity is missing.
     async fn get_for_update<'a>(&self, transaction: &Self::Db<'a>, filter: &F)
         -> Result<Option<T>>;
 }
 
+/// Result of the [Repo::upsert] method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Upserted {
+    Inserted,
+    Updated,
+}
+
+/// Result of the [Repo::get_page] method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    /// Entities on the page.
+    pub items: Vec<T>,
+    /// Cursor to pass to [Query::after](crate::Query::after) to get the next page.
+    /// It's `None` if this page is the last one or the query has no order.
+    pub next_cursor: Option<Cursor>,
+    /// Number of entities matching the query filter on all pages.
+    pub total: i64,
+}
+
+/// Isolation level of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Options for the [Db::transaction_with] method.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionOptions {
+    /// Isolation level. The database default is used if it's `None`.
+    pub isolation_level: Option<IsolationLevel>,
+    /// Forbids modifying data in the transaction.
+    pub read_only: bool,
+    /// Makes a serializable read-only transaction wait for a snapshot
+    /// that can't cause serialization failures.
+    pub deferrable: bool,
+}
+
+impl TransactionOptions {
+    /// Creates options for a transaction with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the `isolation_level` option.
+    pub fn isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+
+    /// Sets the `read_only` option.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Sets the `deferrable` option.
+    pub fn deferrable(mut self) -> Self {
+        self.deferrable = true;
+        self
+    }
+}
+
+/// Policy for re-running transactions that failed
+/// because of a serialization failure or a deadlock.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: usize,
+    /// Delay before the first retry.
+    pub backoff: Duration,
+    /// Factor by which the delay grows after each retry.
+    pub multiplier: u32,
+}
+
+impl RetryPolicy {
+    /// Creates a policy with 3 retries and an exponential backoff
+    /// starting from 10 milliseconds.
+    pub fn new() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+            multiplier: 2,
+        }
+    }
+
+    /// Sets the `max_retries` option.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the `backoff` option.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the `multiplier` option.
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Trait that must be implemented for a database-connection wrapper.
 #[async_trait]
-pub trait Db {
-    async fn transaction<A, T>(&self, action: A) -> Result<T>
+pub trait Db: Sync {
+    /// Executes an action in a transaction with the default options.
+    async fn transaction<A, T>(&self, action: A) -> anyhow::Result<T>
+    where
+        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>
+            + Send,
+        T: Send,
+    {
+        self.transaction_with(TransactionOptions::new(), action)
+            .await
+    }
+
+    /// Executes an action in a transaction with given options.
+    /// The isolation level and the `deferrable` option
+    /// are ignored by nested transactions.
+    async fn transaction_with<A, T>(
+        &self,
+        options: TransactionOptions,
+        action: A,
+    ) -> anyhow::Result<T>
     where
-        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>> + Send,
+        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>
+            + Send,
         T: Send;
+
+    /// Executes an action in a transaction with given options
+    /// and re-runs it according to a retry policy if the transaction fails
+    /// because of a serialization failure or a deadlock.
+    /// The last error is returned when there are no retries left.
+    ///
+    /// Retries are useful only for top-level transactions
+    /// because a nested one can't recover from such failures.
+    async fn transaction_with_retry<A, T>(
+        &self,
+        options: TransactionOptions,
+        retry: RetryPolicy,
+        action: A,
+    ) -> anyhow::Result<T>
+    where
+        A: for<'a> Fn(&'a Self) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>
+            + Send
+            + Sync,
+        T: Send,
+    {
+        let mut retries = 0;
+        let mut delay = retry.backoff;
+
+        loop {
+            match self.transaction_with(options.clone(), &action).await {
+                Err(err) if retries < retry.max_retries && Self::is_retryable(&err) => {
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(retry.multiplier);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Checks if a transaction that failed with a given error can be retried.
+    /// No errors are retryable by default.
+    fn is_retryable(_err: &anyhow::Error) -> bool {
+        false
+    }
 }
@@ -0,0 +1,113 @@
+//! Errors returned by repositories.
+use std::fmt;
+
+/// Error returned by repositories.
+#[derive(Debug)]
+pub enum Error {
+    /// No entity matches a filter.
+    NotFound,
+    /// Several entities match a filter that must match only one.
+    MultipleRows,
+    /// A unique constraint is violated.
+    Conflict(String),
+    /// A foreign key constraint is violated.
+    ForeignKey(String),
+    /// A transaction can't be serialized or is deadlocked,
+    /// so it can be retried.
+    Serialization(String),
+    /// Data can't be modified in a read-only transaction.
+    ReadOnly(String),
+    /// Stored data can't be converted to an entity.
+    Decode(String),
+    /// An entity can't be converted to stored data.
+    Encode(String),
+    /// A filter or an order refers to an unknown field with this name.
+    UnknownField(String),
+    /// A filter or an order uses a field that isn't allowed by a repository.
+    FieldNotAllowed(String),
+    /// A field value has an unexpected type.
+    TypeMismatch(String),
+    /// A query has invalid or inconsistent options.
+    InvalidQuery(String),
+    /// Any other database error.
+    Database(sqlx::Error),
+}
+
+/// Result type returned by repositories.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Checks if a transaction that failed with this error can be retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Serialization(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Entity not found"),
+            Self::MultipleRows => write!(f, "Several entities match the filter"),
+            Self::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            Self::ForeignKey(msg) => write!(f, "Foreign key violation: {}", msg),
+            Self::Serialization(msg) => write!(f, "Serialization failure: {}", msg),
+            Self::ReadOnly(msg) => write!(f, "Read-only transaction: {}", msg),
+            Self::Decode(msg) => write!(f, "Cannot decode an entity: {}", msg),
+            Self::Encode(msg) => write!(f, "Cannot encode an entity: {}", msg),
+            Self::UnknownField(field) => write!(f, "Unknown field {}", field),
+            Self::FieldNotAllowed(field) => write!(f, "Field {} is not allowed", field),
+            Self::TypeMismatch(msg) => write!(f, "Type mismatch: {}", msg),
+            Self::InvalidQuery(msg) => write!(f, "Invalid query: {}", msg),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Extracts the name of a column from a message like `column "x" does not exist`,
+/// so unknown fields are reported the same way by all repositories.
+fn column_name(msg: &str) -> String {
+    let name = msg
+        .strip_prefix("column ")
+        .and_then(|rest| match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next(),
+            None => rest.split(' ').next(),
+        });
+    name.unwrap_or(msg).to_string()
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::ColumnNotFound(_)
+            | sqlx::Error::ColumnIndexOutOfBounds { .. }
+            | sqlx::Error::ColumnDecode { .. }
+            | sqlx::Error::Decode(_) => Self::Decode(err.to_string()),
+            sqlx::Error::Database(ref db_err) => {
+                let msg = db_err.message().to_string();
+                match db_err.code().as_deref() {
+                    Some("23505") => Self::Conflict(msg),
+                    Some("23503") => Self::ForeignKey(msg),
+                    Some("40001") | Some("40P01") => Self::Serialization(msg),
+                    Some("25006") => Self::ReadOnly(msg),
+                    Some("42703") => Self::UnknownField(column_name(&msg)),
+                    Some("42804") | Some("42883") => Self::TypeMismatch(msg),
+                    Some("22025") | Some("2201B") | Some("22P02") | Some("22007") => {
+                        Self::InvalidQuery(msg)
+                    }
+                    _ => Self::Database(err),
+                }
+            }
+            _ => Self::Database(err),
+        }
+    }
+}
@@ -1,22 +1,28 @@
 //! In-memory repository implementation.
+use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::slice;
 use std::str::FromStr;
 
-use anyhow::{bail, Error, Result};
+use anyhow::bail;
 use async_trait::async_trait;
 use chrono::{DateTime, FixedOffset};
-use rust_decimal::Decimal;
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use regex::{Regex, RegexBuilder};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::base::{Db, Repo};
-use crate::query::{Op, Order, Query, F};
+use crate::base::{Db, Page, Repo, TransactionOptions, Upserted};
+use crate::error::{Error, Result};
+use crate::query::{check_aggregation, Agg, AllowedFields, Cursor, Op, Order, Query, F};
 
 /// Repository that stores entities as an in-memory collection
 /// of JSON objects.
@@ -26,6 +32,7 @@ where
     T: Clone + Serialize + for<'de> Deserialize<'de>,
 {
     key: String,
+    allowed_fields: AllowedFields,
     phantom: PhantomData<T>,
 }
 
@@ -36,53 +43,39 @@ where
     pub fn new(key: impl Into<String>) -> Self {
         Self {
             key: key.into(),
+            allowed_fields: AllowedFields::default(),
             phantom: PhantomData,
         }
     }
 
-    fn load(item: Value) -> Result<T> {
-        Ok(serde_json::from_value(item)?)
+    /// Sets fields that can be used in filters.
+    /// Filters with other fields are rejected with [Error::FieldNotAllowed].
+    pub fn filterable(mut self, fields: &[&str]) -> Self {
+        self.allowed_fields.filterable = Some(fields.iter().map(|f| f.to_string()).collect());
+        self
     }
 
-    fn find_index(items: &Vec<Value>, filter: &F) -> Result<Option<usize>> {
-        for (index, item) in items.iter().enumerate() {
-            if matches_filter(item, filter)? {
-                return Ok(Some(index));
-            }
-        }
-        Ok(None)
+    /// Sets fields that can be used in orders.
+    /// Orders with other fields are rejected with [Error::FieldNotAllowed].
+    pub fn sortable(mut self, fields: &[&str]) -> Self {
+        self.allowed_fields.sortable = Some(fields.iter().map(|f| f.to_string()).collect());
+        self
     }
-}
 
-#[async_trait]
-impl<T> Repo<T> for JsonRepo<T>
-where
-    T: Clone + Serialize + for<'de> Deserialize<'de> + Sync + Send,
-{
-    type Db<'a> = JsonDb<'a>;
-
-    async fn get<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<Option<T>> {
-        let mut lock = db.data.write().await;
-        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
-        for item in items {
-            if matches_filter(item, filter)? {
-                return Ok(Some(Self::load(item.clone())?));
-            }
-        }
-        Ok(None)
+    fn load(item: Value) -> Result<T> {
+        serde_json::from_value(item).map_err(|err| Error::Decode(err.to_string()))
     }
 
-    async fn get_many<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Vec<T>> {
-        let mut lock = db.data.write().await;
-        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+    fn select<'v>(items: &'v [Value], query: &Query) -> Result<Vec<&'v Value>> {
         let mut sorted: Vec<&Value> = Vec::new();
         let mut result: Box<dyn Iterator<Item = &Value>> = Box::new(items.iter());
 
         if let Some(filter) = &query.filter {
+            let regexes = compile_regexes(filter)?;
             result = Box::new(
                 result
                     .try_fold(Vec::new(), move |mut acc, x| {
-                        if matches_filter(x, &filter.clone())? {
+                        if matches_filter(x, &filter.clone(), &regexes)? {
                             acc.push(x);
                         }
                         Ok::<Vec<&Value>, Error>(acc)
@@ -99,6 +92,13 @@ where
             result = Box::new(sorted.iter().copied());
         }
 
+        if let Some((order, values)) = query.keyset()? {
+            let values: Vec<&Value> = values.iter().collect();
+            result = Box::new(result.filter(move |x| {
+                vals_cmp(&extract_fields(x, order), &values, order) == Ordering::Greater
+            }));
+        }
+
         if let Some(offset) = query.offset {
             result = Box::new(result.skip(offset));
         }
@@ -107,41 +107,291 @@ where
             result = Box::new(result.take(limit));
         }
 
-        Ok(result.try_fold(Vec::new(), |mut acc, x| {
-            acc.push(Self::load(x.clone())?);
-            Ok::<Vec<T>, Error>(acc)
-        })?)
+        Ok(result.collect())
+    }
+
+    fn find_indices(items: &[Value], filter: &F) -> Result<Vec<usize>> {
+        let regexes = compile_regexes(filter)?;
+        let mut indices = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            if matches_filter(item, filter, &regexes)? {
+                indices.push(index);
+            }
+        }
+        Ok(indices)
+    }
+}
+
+#[async_trait]
+impl<T> Repo<T> for JsonRepo<T>
+where
+    T: Clone + Serialize + for<'de> Deserialize<'de> + Sync + Send,
+{
+    type Db<'a> = JsonDb<'a>;
+
+    async fn get<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<Option<T>> {
+        self.allowed_fields.check_filter(filter)?;
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        let regexes = compile_regexes(filter)?;
+        for item in items {
+            if matches_filter(item, filter, &regexes)? {
+                return Ok(Some(Self::load(item.clone())?));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_one<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<T> {
+        self.allowed_fields.check_filter(filter)?;
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+
+        match Self::find_indices(items, filter)?[..] {
+            [] => Err(Error::NotFound),
+            [index] => Self::load(items[index].clone()),
+            _ => Err(Error::MultipleRows),
+        }
+    }
+
+    async fn get_many<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Vec<T>> {
+        self.allowed_fields.check_query(query)?;
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        Self::select(items, query)?
+            .into_iter()
+            .map(|item| Self::load(item.clone()))
+            .collect()
+    }
+
+    async fn project<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        query: &Query,
+        fields: &[&str],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send,
+    {
+        if fields.is_empty() {
+            return Err(Error::InvalidQuery("No fields to select".to_string()));
+        }
+        self.allowed_fields.check_fields(fields.iter().copied())?;
+        self.allowed_fields.check_query(query)?;
+
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+
+        Self::select(items, query)?
+            .into_iter()
+            .map(|item| {
+                let mut row = serde_json::Map::new();
+                for field in fields {
+                    row.insert(field.to_string(), get_field(item, field)?.clone());
+                }
+                serde_json::from_value(Value::Object(row))
+                    .map_err(|err| Error::Decode(err.to_string()))
+            })
+            .collect()
+    }
+
+    async fn get_page<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Page<T>> {
+        self.allowed_fields.check_query(query)?;
+        let mut lock = db.data.write().await;
+        let all_items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        // One more item is selected to find out if there is a next page.
+        let mut page_query = query.clone();
+        page_query.limit = query.limit.map(|limit| limit.saturating_add(1));
+        let mut items = Self::select(all_items, &page_query)?;
+        let limit = query.limit.unwrap_or(items.len());
+
+        let total = match &query.filter {
+            Some(filter) => Self::find_indices(all_items, filter)?.len() as i64,
+            None => all_items.len() as i64,
+        };
+
+        let next_cursor = match &query.order {
+            Some(order) if limit > 0 && items.len() > limit => {
+                let values = extract_fields(items[limit - 1], order);
+                Some(Cursor::new(values.into_iter().cloned().collect()))
+            }
+            _ => None,
+        };
+
+        items.truncate(limit);
+
+        Ok(Page {
+            items: items
+                .into_iter()
+                .map(|item| Self::load(item.clone()))
+                .collect::<Result<_>>()?,
+            next_cursor,
+            total,
+        })
+    }
+
+    /// Finds several entities and returns them as a stream.
+    /// Without an order, entities are matched lazily as the stream is consumed.
+    fn stream<'a, 'b>(
+        &'b self,
+        db: &'b Self::Db<'a>,
+        query: &'b Query,
+    ) -> BoxStream<'b, Result<T>> {
+        // The stream goes over a snapshot of the items,
+        // so the data isn't locked while the stream is consumed.
+        let items = async move {
+            self.allowed_fields.check_query(query)?;
+            query.keyset()?;
+            let snapshot: Vec<Value> = match db.data.read().await.get(&self.key) {
+                Some(items) => items.clone(),
+                None => Vec::new(),
+            };
+
+            // Sorted entities can't be returned before all of them are matched.
+            if query.order.is_some() {
+                let items: Vec<Value> = Self::select(&snapshot, query)?
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                return Ok::<_, Error>(stream::iter(items).map(Self::load).boxed());
+            }
+
+            let regexes = match &query.filter {
+                Some(filter) => compile_regexes(filter)?,
+                None => Regexes::new(),
+            };
+            let mut offset = query.offset.unwrap_or(0);
+            let items = snapshot
+                .into_iter()
+                .filter_map(move |item| {
+                    let matches = match &query.filter {
+                        Some(filter) => matches_filter(&item, filter, &regexes),
+                        None => Ok(true),
+                    };
+                    match matches {
+                        Ok(true) if offset > 0 => {
+                            offset -= 1;
+                            None
+                        }
+                        Ok(true) => Some(Self::load(item)),
+                        Ok(false) => None,
+                        Err(err) => Some(Err(err)),
+                    }
+                })
+                .take(query.limit.unwrap_or(usize::MAX));
+            Ok(stream::iter(items).boxed())
+        };
+        Box::pin(stream::once(items).try_flatten())
     }
 
     async fn add<'a>(&self, db: &Self::Db<'a>, entity: &T) -> Result<()> {
-        let item = serde_json::to_value(entity)?;
+        db.check_writable()?;
+        let item = encode(entity)?;
         let mut lock = db.data.write().await;
         let items = lock.entry(self.key.clone()).or_insert(Vec::new());
         items.push(item);
         Ok(())
     }
 
-    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<()> {
+    async fn add_many<'a>(&self, db: &Self::Db<'a>, entities: &[T]) -> Result<()> {
+        db.check_writable()?;
+        let new_items = entities.iter().map(encode).collect::<Result<Vec<_>>>()?;
         let mut lock = db.data.write().await;
         let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        items.extend(new_items);
+        Ok(())
+    }
 
-        while let Some(index) = Self::find_index(items, filter)? {
-            items.remove(index);
+    async fn upsert<'a>(
+        &self,
+        db: &Self::Db<'a>,
+        entity: &T,
+        conflict_target: &[&str],
+    ) -> Result<Upserted> {
+        db.check_writable()?;
+        if conflict_target.is_empty() {
+            return Err(Error::InvalidQuery("No conflict target".to_string()));
+        }
+        let item = encode(entity)?;
+        let mut key = Vec::new();
+        for field in conflict_target {
+            match lookup(&item, field) {
+                Some(val) => key.push(val),
+                None => return Err(Error::UnknownField(field.to_string())),
+            }
         }
 
-        Ok(())
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        // Nulls are distinct in unique indexes, so they never conflict.
+        let index = if key.iter().any(|val| val.is_null()) {
+            None
+        } else {
+            items.iter().position(|x| {
+                conflict_target
+                    .iter()
+                    .zip(&key)
+                    .all(|(field, val)| lookup(x, field) == Some(val))
+            })
+        };
+
+        match index {
+            Some(index) => {
+                items[index] = item;
+                Ok(Upserted::Updated)
+            }
+            None => {
+                items.push(item);
+                Ok(Upserted::Inserted)
+            }
+        }
     }
 
-    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()> {
+    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<u64> {
+        db.check_writable()?;
+        self.allowed_fields.check_filter(filter)?;
         let mut lock = db.data.write().await;
         let items = lock.entry(self.key.clone()).or_insert(Vec::new());
-        let item = serde_json::to_value(entity)?;
+        let indices = Self::find_indices(items, filter)?;
 
-        if let Some(index) = Self::find_index(items, filter)? {
-            items[index] = item;
+        for index in indices.iter().rev() {
+            items.remove(*index);
         }
 
-        Ok(())
+        Ok(indices.len() as u64)
+    }
+
+    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<u64> {
+        db.check_writable()?;
+        self.allowed_fields.check_filter(filter)?;
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        let item = encode(entity)?;
+        let mut count = 0;
+
+        for index in Self::find_indices(items, filter)? {
+            items[index] = item.clone();
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    async fn update_one<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()> {
+        db.check_writable()?;
+        self.allowed_fields.check_filter(filter)?;
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        let item = encode(entity)?;
+
+        match Self::find_indices(items, filter)?[..] {
+            [] => Err(Error::NotFound),
+            [index] => {
+                items[index] = item;
+                Ok(())
+            }
+            _ => Err(Error::MultipleRows),
+        }
     }
 
     async fn exists<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<bool> {
@@ -150,12 +400,14 @@ where
     }
 
     async fn count<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<i64> {
+        self.allowed_fields.check_filter(filter)?;
         let mut lock = db.data.write().await;
         let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        let regexes = compile_regexes(filter)?;
         let mut count = 0;
 
         for item in items {
-            if matches_filter(item, filter)? {
+            if matches_filter(item, filter, &regexes)? {
                 count += 1
             }
         }
@@ -169,19 +421,137 @@ where
         Ok(items.len() as i64)
     }
 
+    async fn aggregate<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        filter: Option<&F>,
+        group_by: &[&str],
+        aggs: &[Agg],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send,
+    {
+        check_aggregation(group_by, aggs)?;
+        self.allowed_fields.check_fields(
+            group_by
+                .iter()
+                .copied()
+                .chain(aggs.iter().filter_map(Agg::field)),
+        )?;
+        if let Some(filter) = filter {
+            self.allowed_fields.check_filter(filter)?;
+        }
+
+        let mut lock = db.data.write().await;
+        let items = lock.entry(self.key.clone()).or_insert(Vec::new());
+        let items: Vec<&Value> = match filter {
+            Some(filter) => Self::find_indices(items, filter)?
+                .into_iter()
+                .map(|index| &items[index])
+                .collect(),
+            None => items.iter().collect(),
+        };
+
+        let mut groups: Vec<(Vec<&Value>, Vec<&Value>)> = Vec::new();
+        for item in items {
+            let key = group_by
+                .iter()
+                .map(|field| get_field(item, field))
+                .collect::<Result<Vec<_>>>()?;
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, group)) => group.push(item),
+                None => groups.push((key, vec![item])),
+            }
+        }
+
+        // Like in SQL, aggregate functions without grouping
+        // return one row even if there are no entities.
+        if group_by.is_empty() && groups.is_empty() {
+            groups.push((Vec::new(), Vec::new()));
+        }
+
+        let order: Vec<Order> = group_by
+            .iter()
+            .map(|field| Order::Asc(field.to_string()))
+            .collect();
+        groups.sort_by(|(x, _), (y, _)| vals_cmp(x, y, &order));
+
+        groups
+            .into_iter()
+            .map(|(key, group)| {
+                let mut row = serde_json::Map::new();
+                for (field, val) in group_by.iter().zip(key) {
+                    row.insert(field.to_string(), val.clone());
+                }
+                for agg in aggs {
+                    row.insert(agg.alias(), aggregate(agg, &group)?);
+                }
+                serde_json::from_value(Value::Object(row))
+                    .map_err(|err| Error::Decode(err.to_string()))
+            })
+            .collect()
+    }
+
     async fn get_for_update<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<Option<T>> {
         self.get(db, filter).await
     }
 }
 
-fn matches_filter(v: &Value, f: &F) -> Result<bool> {
+/// Compiled regular expressions of a filter by their patterns and case sensitivity.
+type Regexes<'f> = HashMap<(&'f str, bool), Regex>;
+
+/// Compiles regular expressions of a filter once before it's matched with items,
+/// so an invalid pattern fails even if there are no items to match.
+fn compile_regexes(f: &F) -> Result<Regexes<'_>> {
+    fn collect<'f>(f: &'f F, regexes: &mut Regexes<'f>) -> Result<()> {
+        match f {
+            F::And(filters) | F::Or(filters) => {
+                for filter in filters {
+                    collect(filter, regexes)?;
+                }
+            }
+            F::Not(filter) => collect(filter, regexes)?,
+            F::Value {
+                op: Op::StrMatches(pattern),
+                ..
+            } => {
+                regexes.insert((pattern, false), compile_regex(pattern, false)?);
+            }
+            F::Value {
+                op: Op::StrIMatches(pattern),
+                ..
+            } => {
+                regexes.insert((pattern, true), compile_regex(pattern, true)?);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    let mut regexes = Regexes::new();
+    collect(f, &mut regexes)?;
+    Ok(regexes)
+}
+
+/// Returns a regular expression compiled by `compile_regexes`.
+fn find_regex<'r>(
+    regexes: &'r Regexes<'r>,
+    pattern: &'r str,
+    case_insensitive: bool,
+) -> Result<&'r Regex> {
+    regexes.get(&(pattern, case_insensitive)).ok_or_else(|| {
+        Error::InvalidQuery(format!("Regular expression {} isn't compiled", pattern))
+    })
+}
+
+fn matches_filter(v: &Value, f: &F, regexes: &Regexes) -> Result<bool> {
     Ok(match f {
-        F::And(filters) => matches_all_filters(v, filters)?,
-        F::Or(filters) => matches_any_filter(v, filters)?,
-        F::Not(filter) => !matches_filter(v, filter)?,
-        F::IsNone(field) => v[field].is_null(),
+        F::And(filters) => matches_all_filters(v, filters, regexes)?,
+        F::Or(filters) => matches_any_filter(v, filters, regexes)?,
+        F::Not(filter) => !matches_filter(v, filter, regexes)?,
+        F::IsNone(field) => lookup(v, field).is_none_or(Value::is_null),
         F::Value { field, op } => {
-            if let Some(val) = v.get(field) {
+            if let Some(val) = lookup(v, field) {
                 if val.is_null() {
                     return Ok(false);
                 }
@@ -189,9 +559,51 @@ fn matches_filter(v: &Value, f: &F) -> Result<bool> {
                     Op::StrEq(arg) => extract_string(val)? == arg,
                     Op::StrNe(arg) => extract_string(val)? != arg,
                     Op::StrContains(arg) => extract_string(val)?.contains(arg),
+                    Op::StrLike(arg) => matches_like(extract_string(val)?, arg)?,
                     Op::StrStartsWith(arg) => extract_string(val)?.starts_with(arg),
                     Op::StrEndsWith(arg) => extract_string(val)?.ends_with(arg),
+                    Op::StrIEq(arg) => fold_case(extract_string(val)?) == fold_case(arg),
+                    Op::StrIContains(arg) => {
+                        fold_case(extract_string(val)?).contains(&fold_case(arg))
+                    }
+                    Op::StrIStartsWith(arg) => {
+                        fold_case(extract_string(val)?).starts_with(&fold_case(arg))
+                    }
+                    Op::StrIEndsWith(arg) => {
+                        fold_case(extract_string(val)?).ends_with(&fold_case(arg))
+                    }
+                    Op::StrMatches(arg) => {
+                        find_regex(regexes, arg, false)?.is_match(extract_string(val)?)
+                    }
+                    Op::StrIMatches(arg) => {
+                        find_regex(regexes, arg, true)?.is_match(extract_string(val)?)
+                    }
                     Op::StrIn(arg) => arg.contains(extract_string(val)?),
+                    Op::StrArrayHas(arg) => {
+                        matches_array(val, extract_string, slice::from_ref(arg), true)?
+                    }
+                    Op::StrArrayHasAll(arg) => matches_array(val, extract_string, arg, true)?,
+                    Op::StrArrayOverlaps(arg) => matches_array(val, extract_string, arg, false)?,
+                    Op::IntArrayHas(arg) => {
+                        matches_array(val, extract_int, slice::from_ref(arg), true)?
+                    }
+                    Op::IntArrayHasAll(arg) => matches_array(val, extract_int, arg, true)?,
+                    Op::IntArrayOverlaps(arg) => matches_array(val, extract_int, arg, false)?,
+                    Op::UuidArrayHas(arg) => {
+                        matches_array(val, extract_uuid, slice::from_ref(arg), true)?
+                    }
+                    Op::UuidArrayHasAll(arg) => matches_array(val, extract_uuid, arg, true)?,
+                    Op::UuidArrayOverlaps(arg) => matches_array(val, extract_uuid, arg, false)?,
+                    Op::JsonContains(arg) => json_contains(val, arg, true),
+                    Op::JsonHasKey(arg) => match val {
+                        Value::Object(map) => map.contains_key(arg),
+                        Value::Array(items) => items.iter().any(|item| item.as_str() == Some(arg)),
+                        Value::String(s) => s == arg,
+                        _ => false,
+                    },
+                    Op::JsonPathEq(path, arg) => {
+                        json_path(val, path).is_some_and(|val| json_eq(val, arg))
+                    }
                     Op::DateTimeEq(arg) => extract_date_time(val)? == *arg,
                     Op::DateTimeNe(arg) => extract_date_time(val)? != *arg,
                     Op::DateTimeLt(arg) => extract_date_time(val)? < *arg,
@@ -226,26 +638,234 @@ fn matches_filter(v: &Value, f: &F) -> Result<bool> {
                     Op::FloatGte(arg) => extract_float(val)? >= *arg,
                     Op::BoolEq(arg) => extract_bool(val)? == *arg,
                     Op::BoolNe(arg) => extract_bool(val)? != *arg,
+                    Op::FieldEq(other_field)
+                    | Op::FieldNe(other_field)
+                    | Op::FieldLt(other_field)
+                    | Op::FieldGt(other_field)
+                    | Op::FieldLte(other_field)
+                    | Op::FieldGte(other_field) => {
+                        let other_val = get_field(v, other_field)?;
+                        if other_val.is_null() {
+                            return Ok(false);
+                        }
+                        let ordering = compare_fields(val, other_val)?;
+                        match op {
+                            Op::FieldEq(_) => ordering == Ordering::Equal,
+                            Op::FieldNe(_) => ordering != Ordering::Equal,
+                            Op::FieldLt(_) => ordering == Ordering::Less,
+                            Op::FieldGt(_) => ordering == Ordering::Greater,
+                            Op::FieldLte(_) => ordering != Ordering::Greater,
+                            _ => ordering != Ordering::Less,
+                        }
+                    }
                 }
             } else {
-                bail!("Unknown field {}", field)
+                return Err(Error::UnknownField(field.clone()));
             }
         }
     })
 }
 
-fn matches_all_filters(v: &Value, filters: &[F]) -> Result<bool> {
+/// Matches a string against a LIKE pattern like PostgreSQL does.
+fn matches_like(s: &str, pattern: &str) -> Result<bool> {
+    enum Token {
+        Any,
+        One,
+        Char(char),
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '%' => Token::Any,
+            '_' => Token::One,
+            '\\' => match chars.next() {
+                Some(c) => Token::Char(c),
+                None => {
+                    return Err(Error::InvalidQuery(
+                        "LIKE pattern must not end with escape character".to_string(),
+                    ))
+                }
+            },
+            c => Token::Char(c),
+        });
+    }
+
+    // Greedy matching that backtracks to the last `%` on a mismatch.
+    let s: Vec<char> = s.chars().collect();
+    let (mut i, mut t) = (0, 0);
+    let mut backtrack = None;
+    while i < s.len() {
+        match tokens.get(t) {
+            Some(Token::Any) => {
+                backtrack = Some((t, i));
+                t += 1;
+                continue;
+            }
+            Some(Token::One) => {
+                i += 1;
+                t += 1;
+                continue;
+            }
+            Some(Token::Char(c)) if *c == s[i] => {
+                i += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((any_t, any_i)) => {
+                backtrack = Some((any_t, any_i + 1));
+                t = any_t + 1;
+                i = any_i + 1;
+            }
+            None => return Ok(false),
+        }
+    }
+    Ok(tokens[t..].iter().all(|token| matches!(token, Token::Any)))
+}
+
+/// Applies full Unicode case folding, so, for example, `ß` and `SS` become equal.
+fn fold_case(s: &str) -> String {
+    caseless::default_case_fold_str(s)
+}
+
+fn compile_regex(pattern: &str, case_insensitive: bool) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map_err(|err| Error::InvalidQuery(format!("Invalid regular expression: {}", err)))
+}
+
+/// Finds a value of a field. A dotted path like `address.city`
+/// refers to a field of a nested object. Only an unknown top-level field
+/// is missing, a missing nested key is null like in a `jsonb` column.
+fn lookup<'v>(v: &'v Value, field: &str) -> Option<&'v Value> {
+    static NULL: Value = Value::Null;
+    let mut keys = field.split('.');
+    let top = v.get(keys.next()?)?;
+    Some(keys.try_fold(top, |v, key| v.get(key)).unwrap_or(&NULL))
+}
+
+fn get_field<'v>(v: &'v Value, field: &str) -> Result<&'v Value> {
+    lookup(v, field).ok_or_else(|| Error::UnknownField(field.to_string()))
+}
+
+/// Calculates an aggregate function like PostgreSQL does: nulls are skipped,
+/// and sums and averages of integers and decimals are decimals.
+fn aggregate(agg: &Agg, items: &[&Value]) -> Result<Value> {
+    let field = match agg {
+        Agg::Count => return Ok(items.len().into()),
+        Agg::Sum(field) | Agg::Avg(field) | Agg::Min(field) | Agg::Max(field) => field,
+    };
+
+    let mut values = Vec::new();
+    for item in items {
+        let val = get_field(item, field)?;
+        if !val.is_null() {
+            values.push(val);
+        }
+    }
+
+    let result = match agg {
+        // Values are compared like fields, so decimals and date-times
+        // stored as strings are compared by their values.
+        Agg::Min(_) | Agg::Max(_) => {
+            let mut result: Option<&Value> = None;
+            for val in values {
+                let replace = match result {
+                    None => true,
+                    Some(current) => {
+                        let ordering = compare_fields(val, current)?;
+                        if matches!(agg, Agg::Min(_)) {
+                            ordering.is_lt()
+                        } else {
+                            ordering.is_gt()
+                        }
+                    }
+                };
+                if replace {
+                    result = Some(val);
+                }
+            }
+            result.cloned()
+        }
+        _ if values.is_empty() => None,
+        _ => {
+            let count = values.len();
+            let is_avg = matches!(agg, Agg::Avg(_));
+
+            if values.iter().all(|val| val.is_f64()) {
+                let mut sum = 0.0;
+                for val in values {
+                    sum += extract_float(val)?;
+                }
+                Some(if is_avg { sum / count as f64 } else { sum }.into())
+            } else {
+                let mut sum = Decimal::ZERO;
+                for val in values {
+                    sum += match val {
+                        Value::String(_) => extract_decimal(val)?,
+                        _ => Decimal::from(extract_int(val)?),
+                    };
+                }
+                let result = if is_avg { numeric_avg(sum, count) } else { sum };
+                Some(result.to_string().into())
+            }
+        }
+    };
+
+    Ok(result.unwrap_or(Value::Null))
+}
+
+/// Divides a sum by a count with the scale that the `numeric` division
+/// of PostgreSQL selects, so it keeps at least 16 significant digits.
+/// The scale is limited to 28 digits, which is the precision of `Decimal`.
+fn numeric_avg(sum: Decimal, count: usize) -> Decimal {
+    let count = Decimal::from(count);
+    let (sum_weight, sum_digit) = numeric_weight(sum);
+    let (count_weight, count_digit) = numeric_weight(count);
+    let mut weight = sum_weight - count_weight;
+    if sum_digit <= count_digit {
+        weight -= 1;
+    }
+    let scale = (16 - weight * 4).max(sum.scale() as i64).clamp(0, 28);
+    (sum / count).round_dp_with_strategy(scale as u32, RoundingStrategy::MidpointAwayFromZero)
+}
+
+/// Returns the weight and the first digit of a number in base 10000,
+/// which PostgreSQL uses to store `numeric` values.
+fn numeric_weight(d: Decimal) -> (i64, u128) {
+    let mantissa = d.mantissa().unsigned_abs();
+    if mantissa == 0 {
+        return (0, 0);
+    }
+    let digits = mantissa.ilog10() as i64 + 1;
+    let exponent = digits - 1 - d.scale() as i64;
+    let weight = exponent.div_euclid(4);
+    let shift = digits - (exponent - weight * 4 + 1);
+    let digit = if shift >= 0 {
+        mantissa / 10u128.pow(shift as u32)
+    } else {
+        mantissa * 10u128.pow(-shift as u32)
+    };
+    (weight, digit)
+}
+
+fn matches_all_filters(v: &Value, filters: &[F], regexes: &Regexes) -> Result<bool> {
     for f in filters {
-        if !matches_filter(&v, f)? {
+        if !matches_filter(v, f, regexes)? {
             return Ok(false);
         }
     }
     Ok(true)
 }
 
-fn matches_any_filter(v: &Value, filters: &[F]) -> Result<bool> {
+fn matches_any_filter(v: &Value, filters: &[F], regexes: &Regexes) -> Result<bool> {
     for f in filters {
-        if matches_filter(&v, f)? {
+        if matches_filter(v, f, regexes)? {
             return Ok(true);
         }
     }
@@ -256,23 +876,23 @@ fn extract_string(v: &Value) -> Result<&String> {
     if let Value::String(s) = v {
         Ok(s)
     } else {
-        bail!("{:?} is not a string", v)
+        Err(Error::TypeMismatch(format!("{:?} is not a string", v)))
     }
 }
 
 fn extract_date_time(v: &Value) -> Result<DateTime<FixedOffset>> {
     let s = extract_string(v)?;
-    Ok(DateTime::parse_from_rfc3339(&s)?)
+    DateTime::parse_from_rfc3339(s).map_err(|err| Error::TypeMismatch(err.to_string()))
 }
 
 fn extract_decimal(v: &Value) -> Result<Decimal> {
     let s = extract_string(v)?;
-    Ok(Decimal::from_str(s)?)
+    Decimal::from_str(s).map_err(|err| Error::TypeMismatch(err.to_string()))
 }
 
 fn extract_uuid(v: &Value) -> Result<Uuid> {
     let s = extract_string(v)?;
-    Ok(Uuid::parse_str(s)?)
+    Uuid::parse_str(s).map_err(|err| Error::TypeMismatch(err.to_string()))
 }
 
 fn extract_int(v: &Value) -> Result<i64> {
@@ -280,10 +900,13 @@ fn extract_int(v: &Value) -> Result<i64> {
         if let Some(n) = n.as_i64() {
             Ok(n)
         } else {
-            bail!("Cannot convert {:?} to an f64", n);
+            Err(Error::TypeMismatch(format!(
+                "Cannot convert {:?} to i64",
+                n
+            )))
         }
     } else {
-        bail!("{:?} is not a number", v);
+        Err(Error::TypeMismatch(format!("{:?} is not a number", v)))
     }
 }
 
@@ -292,43 +915,197 @@ fn extract_float(v: &Value) -> Result<f64> {
         if let Some(n) = n.as_f64() {
             Ok(n)
         } else {
-            bail!("Cannot convert {:?} to f64", n);
+            Err(Error::TypeMismatch(format!(
+                "Cannot convert {:?} to f64",
+                n
+            )))
         }
     } else {
-        bail!("{:?} is not a number", v);
+        Err(Error::TypeMismatch(format!("{:?} is not a number", v)))
     }
 }
 
+fn extract_array<'v, T>(v: &'v Value, extract: fn(&'v Value) -> Result<T>) -> Result<Vec<T>> {
+    if let Value::Array(items) = v {
+        items.iter().map(extract).collect()
+    } else {
+        Err(Error::TypeMismatch(format!("{:?} is not an array", v)))
+    }
+}
+
+/// Checks if an array contains all the arguments or, if `all` is false, any of them.
+fn matches_array<'v, T, A>(
+    v: &'v Value,
+    extract: fn(&'v Value) -> Result<T>,
+    args: &[A],
+    all: bool,
+) -> Result<bool>
+where
+    T: Borrow<A>,
+    A: PartialEq,
+{
+    let items = extract_array(v, extract)?;
+    let contains = |arg: &A| items.iter().any(|item| item.borrow() == arg);
+    Ok(if all {
+        args.iter().all(contains)
+    } else {
+        args.iter().any(contains)
+    })
+}
+
 fn extract_bool(v: &Value) -> Result<bool> {
     if let Value::Bool(b) = v {
         Ok(*b)
     } else {
-        bail!("{:?} is not a bool", v);
+        Err(Error::TypeMismatch(format!("{:?} is not a bool", v)))
+    }
+}
+
+fn number_to_decimal(n: &serde_json::Number) -> Result<Decimal> {
+    let s = n.to_string();
+    Decimal::from_str(&s)
+        .or_else(|_| Decimal::from_scientific(&s))
+        .map_err(|err| Error::TypeMismatch(err.to_string()))
+}
+
+/// Checks if a JSON value contains another one like the `@>` operator of PostgreSQL.
+/// Only a top-level array can contain a scalar.
+fn json_contains(val: &Value, arg: &Value, top_level: bool) -> bool {
+    match (val, arg) {
+        (Value::Object(val), Value::Object(arg)) => arg.iter().all(|(key, arg)| {
+            val.get(key)
+                .is_some_and(|val| json_contains(val, arg, false))
+        }),
+        (Value::Array(val), Value::Array(arg)) => arg
+            .iter()
+            .all(|arg| val.iter().any(|val| json_contains(val, arg, false))),
+        (Value::Array(val), arg) if top_level && !arg.is_object() => {
+            val.iter().any(|val| json_eq(val, arg))
+        }
+        (val, arg) => json_eq(val, arg),
+    }
+}
+
+/// Compares JSON values. Numbers are equal if they have the same value
+/// even if one of them is an integer and another one is a float.
+fn json_eq(x: &Value, y: &Value) -> bool {
+    match (x, y) {
+        (Value::Number(x), Value::Number(y)) => numbers_eq(x, y),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| json_eq(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(key, x)| y.get(key).is_some_and(|y| json_eq(x, y)))
+        }
+        _ => x == y,
+    }
+}
+
+/// Checks if two numbers are equal like `jsonb` numbers, which are compared as decimals.
+/// Integers are compared exactly, so large ones aren't rounded to floats.
+fn numbers_eq(x: &serde_json::Number, y: &serde_json::Number) -> bool {
+    if x.is_f64() || y.is_f64() {
+        match (number_to_decimal(x), number_to_decimal(y)) {
+            (Ok(x), Ok(y)) => x == y,
+            _ => x.as_f64() == y.as_f64(),
+        }
+    } else if let (Some(x), Some(y)) = (x.as_i64(), y.as_i64()) {
+        x == y
+    } else {
+        x.as_u64() == y.as_u64()
     }
 }
 
-fn extract_fields<'a>(v: &'a Value, order: &Vec<Order>) -> Vec<&'a Value> {
+/// Follows a path of object keys and array indices like the `#>` operator of PostgreSQL.
+fn json_path<'v>(v: &'v Value, path: &[String]) -> Option<&'v Value> {
+    path.iter().try_fold(v, |v, key| match v {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// A string of unknown type prepared for comparison. Date-times go before
+/// decimals and decimals go before other text, so the order stays transitive.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum StrKey<'a> {
+    DateTime(DateTime<FixedOffset>),
+    Decimal(Decimal),
+    Text(&'a str),
+}
+
+impl<'a> StrKey<'a> {
+    fn new(s: &'a str) -> Self {
+        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+            Self::DateTime(dt)
+        } else if let Ok(d) = Decimal::from_str(s) {
+            Self::Decimal(d)
+        } else {
+            Self::Text(s)
+        }
+    }
+}
+
+/// Compares values of two fields. Strings that can be parsed as date-times
+/// or decimals are compared by their values, since their actual types are unknown.
+/// Unlike text columns of PostgreSQL, it puts `"9"` before `"10"`.
+fn compare_fields(x: &Value, y: &Value) -> Result<Ordering> {
+    match (x, y) {
+        (Value::Number(_), Value::Number(_)) => match (extract_int(x), extract_int(y)) {
+            (Ok(x), Ok(y)) => Ok(x.cmp(&y)),
+            _ => Ok(extract_float(x)?.total_cmp(&extract_float(y)?)),
+        },
+        (Value::String(s1), Value::String(s2)) => Ok(StrKey::new(s1).cmp(&StrKey::new(s2))),
+        (Value::Number(n), Value::String(_)) => Ok(number_to_decimal(n)?.cmp(&extract_decimal(y)?)),
+        (Value::String(_), Value::Number(n)) => Ok(extract_decimal(x)?.cmp(&number_to_decimal(n)?)),
+        (Value::Bool(b1), Value::Bool(b2)) => Ok(b1.cmp(b2)),
+        _ => Err(Error::TypeMismatch(format!(
+            "Cannot compare {:?} with {:?}",
+            x, y
+        ))),
+    }
+}
+
+fn encode<T: Serialize>(entity: &T) -> Result<Value> {
+    serde_json::to_value(entity).map_err(|err| Error::Encode(err.to_string()))
+}
+
+fn extract_fields<'a>(v: &'a Value, order: &[Order]) -> Vec<&'a Value> {
     order
         .iter()
         .map(|f| match f {
             Order::Asc(field) => field,
             Order::Desc(field) => field,
         })
-        .map(|f| &v[f])
+        .map(|f| lookup(v, f).unwrap_or(&Value::Null))
         .collect()
 }
 
+/// Orders values of different types by their types, so sorting stays consistent.
+fn type_rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
 fn val_cmp(x: &Value, y: &Value, order: &Order) -> Ordering {
     let result = match (x, y) {
         (Value::Null, Value::Null) => Ordering::Equal,
         (Value::Null, _) => Ordering::Less,
         (_, Value::Null) => Ordering::Greater,
-        (Value::String(s1), Value::String(s2)) => s1.cmp(&s2),
+        (Value::String(s1), Value::String(s2)) => s1.cmp(s2),
         (Value::Number(n1), Value::Number(n2)) => {
             n1.as_f64().unwrap().total_cmp(&n2.as_f64().unwrap())
         }
         (Value::Bool(b1), Value::Bool(b2)) => b1.cmp(b2),
-        _ => Ordering::Equal,
+        _ => type_rank(x).cmp(&type_rank(y)),
     };
 
     match order {
@@ -341,7 +1118,7 @@ fn val_cmp(x: &Value, y: &Value, order: &Order) -> Ordering {
     }
 }
 
-fn vals_cmp(xs: &Vec<&Value>, ys: &Vec<&Value>, fields: &Vec<Order>) -> Ordering {
+fn vals_cmp(xs: &[&Value], ys: &[&Value], fields: &[Order]) -> Ordering {
     for ((x, y), order) in xs.iter().zip(ys.iter()).zip(fields.iter()) {
         match val_cmp(x, y, order) {
             Ordering::Greater => return Ordering::Greater,
@@ -354,8 +1131,14 @@ fn vals_cmp(xs: &Vec<&Value>, ys: &Vec<&Value>, fields: &Vec<Order>) -> Ordering
 }
 
 /// Struct that contains a collection of JSON objects.
+///
+/// Transactions see a snapshot of the data taken when they begin
+/// and publish their changes only on commit. Conflicts are detected
+/// per collection: a commit fails if another transaction has already
+/// committed changes to a collection that this one has modified.
 pub struct JsonDb<'a> {
     data: RwLock<HashMap<String, Vec<Value>>>,
+    read_only: bool,
     phantom: PhantomData<&'a ()>,
 }
 
@@ -363,28 +1146,81 @@ impl<'a> JsonDb<'a> {
     pub fn new() -> Self {
         Self {
             data: RwLock::new(HashMap::new()),
+            read_only: false,
             phantom: PhantomData,
         }
     }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly(
+                "Cannot modify data in a read-only transaction".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for JsonDb<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[async_trait]
 impl Db for JsonDb<'_> {
-    async fn transaction<A, T>(&self, action: A) -> Result<T>
+    async fn transaction_with<A, T>(
+        &self,
+        options: TransactionOptions,
+        action: A,
+    ) -> anyhow::Result<T>
     where
-        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>> + Send,
+        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>
+            + Send,
         T: Send,
     {
-        let initial_state = self.data.read().await.clone();
+        // The transaction works on its own copy of the data, so its changes
+        // are invisible to others until it is committed. A nested transaction
+        // copies the data of the outer one, so it acts as a savepoint.
+        // Isolation levels are ignored because every transaction
+        // already works with its own snapshot.
+        let snapshot = self.data.read().await.clone();
+        let tx = Self {
+            data: RwLock::new(snapshot.clone()),
+            read_only: self.read_only || options.read_only,
+            phantom: PhantomData,
+        };
 
-        match action(&self).await {
-            Ok(res) => Ok(res),
-            Err(err) => {
+        match action(&tx).await {
+            Ok(res) => {
                 let mut data = self.data.write().await;
-                data.clear();
-                data.extend(initial_state);
-                bail!(err)
+                let empty = Vec::new();
+                let changes: Vec<(String, Vec<Value>)> = tx
+                    .data
+                    .into_inner()
+                    .into_iter()
+                    .filter(|(key, items)| items != snapshot.get(key).unwrap_or(&empty))
+                    .collect();
+
+                for (key, _) in &changes {
+                    if data.get(key).unwrap_or(&empty) != snapshot.get(key).unwrap_or(&empty) {
+                        return Err(Error::Serialization(format!(
+                            "Collection {} was modified by a concurrent transaction",
+                            key
+                        ))
+                        .into());
+                    }
+                }
+
+                data.extend(changes);
+                Ok(res)
             }
+            Err(err) => bail!(err),
         }
     }
+
+    fn is_retryable(err: &anyhow::Error) -> bool {
+        err.chain()
+            .any(|e| matches!(e.downcast_ref::<Error>(), Some(e) if e.is_retryable()))
+    }
 }
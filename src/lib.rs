@@ -1,5 +1,6 @@
 #![doc = include_str!("lib.md")]
 pub mod base;
+pub mod error;
 pub mod json;
 pub mod pg;
 pub mod query;
@@ -7,4 +8,6 @@ pub mod query;
 #[doc(inline)]
 pub use self::base::{Db, Repo};
 #[doc(inline)]
-pub use self::query::{Filter, Order, Query, F, Q};
+pub use self::error::Error;
+#[doc(inline)]
+pub use self::query::{Agg, Cursor, Field, Filter, Order, Query, F, Q};
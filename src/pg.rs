@@ -2,19 +2,36 @@
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use anyhow::{bail, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use futures_util::{future, FutureExt};
 use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
 use sqlx::database::HasArguments;
 use sqlx::postgres::PgRow;
-use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder, Row};
-use tokio::sync::RwLock;
+use sqlx::types::Json;
+use sqlx::{
+    Column, Connection, Encode, Executor, PgConnection, PgPool, Postgres, QueryBuilder, Row, Type,
+    TypeInfo, ValueRef,
+};
+use tokio::sync::{mpsc, OnceCell, RwLock};
 use uuid::Uuid;
 
-use crate::base::{Db, Repo};
-use crate::query::{Op, Order, Query, F};
+use crate::base::{Db, IsolationLevel, Page, Repo, TransactionOptions, Upserted};
+use crate::error::{Error, Result};
+use crate::query::{check_aggregation, Agg, AllowedFields, Cursor, Op, Order, Query, F};
+
+/// Maximum number of bind parameters in one PostgreSQL query.
+const MAX_BINDS: usize = 65535;
+
+/// Number of rows sent to PostgreSQL in one message by the `COPY` command.
+const COPY_CHUNK_SIZE: usize = 1000;
+
+/// Number of entities fetched in advance by a stream.
+const STREAM_BUFFER_SIZE: usize = 100;
 
 /// Value that can be saved to a database.
 pub enum Value {
@@ -29,6 +46,10 @@ pub enum Value {
     Decimal(Decimal),
     Uuid(Uuid),
     DateTime(DateTime<Utc>),
+    Json(serde_json::Value),
+    StrArray(Vec<String>),
+    Int64Array(Vec<i64>),
+    UuidArray(Vec<Uuid>),
     Null,
 }
 
@@ -46,9 +67,59 @@ impl Value {
             Self::Decimal(val) => builder.push_bind(*val),
             Self::Uuid(val) => builder.push_bind(*val),
             Self::DateTime(val) => builder.push_bind(*val),
+            Self::Json(val) => builder.push_bind(Json(val.clone())),
+            Self::StrArray(val) => builder.push_bind(val.clone()),
+            Self::Int64Array(val) => builder.push_bind(val.clone()),
+            Self::UuidArray(val) => builder.push_bind(val.clone()),
             Self::Null => builder.push("null"),
         };
     }
+
+    fn write_copy_text(&self, buf: &mut String) {
+        match self {
+            Self::Str(val) => write_copy_str(val, buf),
+            Self::Int8(val) => buf.push_str(&val.to_string()),
+            Self::Int16(val) => buf.push_str(&val.to_string()),
+            Self::Int32(val) => buf.push_str(&val.to_string()),
+            Self::Int64(val) => buf.push_str(&val.to_string()),
+            Self::Float32(val) => buf.push_str(&val.to_string()),
+            Self::Float64(val) => buf.push_str(&val.to_string()),
+            Self::Bool(val) => buf.push(if *val { 't' } else { 'f' }),
+            Self::Decimal(val) => buf.push_str(&val.to_string()),
+            Self::Uuid(val) => buf.push_str(&val.to_string()),
+            Self::DateTime(val) => buf.push_str(&val.to_rfc3339()),
+            Self::Json(val) => write_copy_str(&val.to_string(), buf),
+            Self::StrArray(val) => write_copy_str(&array_literal(val), buf),
+            Self::Int64Array(val) => write_copy_str(&array_literal(val), buf),
+            Self::UuidArray(val) => write_copy_str(&array_literal(val), buf),
+            Self::Null => buf.push_str("\\N"),
+        }
+    }
+}
+
+/// Formats an array in the text format of PostgreSQL.
+fn array_literal<T: ToString>(values: &[T]) -> String {
+    let items: Vec<String> = values
+        .iter()
+        .map(|val| {
+            let val = val.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{}\"", val)
+        })
+        .collect();
+    format!("{{{}}}", items.join(","))
+}
+
+/// Writes a string in the text format of the `COPY` command.
+fn write_copy_str(val: &str, buf: &mut String) {
+    for c in val.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '\t' => buf.push_str("\\t"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            c => buf.push(c),
+        }
+    }
 }
 
 impl From<String> for Value {
@@ -117,6 +188,30 @@ impl From<DateTime<Utc>> for Value {
     }
 }
 
+impl From<Vec<String>> for Value {
+    fn from(value: Vec<String>) -> Self {
+        Self::StrArray(value)
+    }
+}
+
+impl From<Vec<i64>> for Value {
+    fn from(value: Vec<i64>) -> Self {
+        Self::Int64Array(value)
+    }
+}
+
+impl From<Vec<Uuid>> for Value {
+    fn from(value: Vec<Uuid>) -> Self {
+        Self::UuidArray(value)
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        Self::Json(value)
+    }
+}
+
 impl<T> From<Option<T>> for Value
 where
     T: Into<Value>,
@@ -141,6 +236,11 @@ pub struct PgRepo<T> {
     load: fn(row: &PgRow) -> T,
     after_add_hook: Option<fn(&T) -> Vec<PgQuery>>,
     after_update_hook: Option<fn(&T) -> Vec<PgQuery>>,
+    allowed_fields: AllowedFields,
+    json_columns: Vec<String>,
+    /// Types of the columns of the query results, which are used to bind cursor values.
+    /// They are described once and shared by clones of the repository.
+    column_types: Arc<OnceCell<HashMap<String, String>>>,
 }
 
 impl<T> PgRepo<T> {
@@ -159,6 +259,9 @@ impl<T> PgRepo<T> {
             query,
             after_add_hook: None,
             after_update_hook: None,
+            allowed_fields: AllowedFields::default(),
+            json_columns: Vec::new(),
+            column_types: Arc::default(),
         }
     }
 
@@ -181,6 +284,7 @@ impl<T> PgRepo<T> {
         }
 
         self.query = builder.sql().to_string();
+        self.column_types = Arc::default();
         self
     }
 
@@ -193,14 +297,133 @@ impl<T> PgRepo<T> {
 
     /// Sets a function that returns a vector of queries
     /// to execute after an updated entity is saved to a database.
+    /// The queries aren't executed if the update doesn't match any rows.
     pub fn after_update(mut self, hook: fn(&T) -> Vec<PgQuery>) -> Self {
         self.after_update_hook = Some(hook);
         self
     }
 
-    fn apply_filter(&self, builder: &mut QueryBuilder<Postgres>, filter: &F) {
+    /// Loads entities to a database with the `COPY` command.
+    /// It's much faster than [Repo::add_many] for large amounts of data.
+    /// Returns the number of loaded rows.
+    pub async fn copy_in(&self, db: &PgDb<'_>, entities: &[T]) -> Result<u64> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.copy_in_via(&mut conn, entities).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.copy_in_via(&mut t, entities).await
+            }
+        }
+    }
+
+    /// Sets fields that can be used in filters.
+    /// Filters with other fields are rejected with [Error::FieldNotAllowed].
+    pub fn filterable(mut self, fields: &[&str]) -> Self {
+        self.allowed_fields.filterable = Some(fields.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    /// Sets fields that can be used in orders.
+    /// Orders with other fields are rejected with [Error::FieldNotAllowed].
+    pub fn sortable(mut self, fields: &[&str]) -> Self {
+        self.allowed_fields.sortable = Some(fields.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    /// Sets `jsonb` columns. Filters and orders can refer to nested fields
+    /// of these columns with dotted paths like `address.city`.
+    pub fn json_columns(mut self, columns: &[&str]) -> Self {
+        self.json_columns = columns.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Splits a field into a `jsonb` column and a path inside it.
+    /// Returns `None` if the field isn't a nested field of a `jsonb` column.
+    fn json_path<'f>(&self, field: &'f str) -> Option<(&'f str, Vec<&'f str>)> {
+        let mut parts = field.split('.');
+        let column = parts.next()?;
+        let path: Vec<&str> = parts.collect();
+
+        if path.is_empty() || !self.json_columns.iter().any(|c| c == column) {
+            None
+        } else {
+            Some((column, path))
+        }
+    }
+
+    /// Returns an expression that selects a field as `jsonb`.
+    fn json_field(&self, field: &str) -> String {
+        match self.json_path(field) {
+            Some((column, path)) => {
+                let mut sql = quote(column);
+                for key in path {
+                    sql.push_str("->");
+                    sql.push_str(&quote_literal(key));
+                }
+                sql
+            }
+            None => quote(field),
+        }
+    }
+
+    /// Returns an expression that selects a field as a value
+    /// of the type expected by an operation.
+    fn typed_field(&self, field: &str, op: Option<&Op>) -> String {
+        match self.json_path(field) {
+            Some((column, path)) => {
+                let mut sql = quote(column);
+                for (n, key) in path.iter().enumerate() {
+                    sql.push_str(if n + 1 == path.len() { "->>" } else { "->" });
+                    sql.push_str(&quote_literal(key));
+                }
+                match op.and_then(json_cast) {
+                    Some(cast) => format!("({})::{}", sql, cast),
+                    None => format!("({})", sql),
+                }
+            }
+            None => quote(field),
+        }
+    }
+
+    /// Returns expressions that select two fields in comparable form.
+    /// A plain column is converted to `jsonb` if it's compared with a nested field.
+    fn field_pair(&self, field: &str, other_field: &str) -> (String, String) {
+        match (self.json_path(field), self.json_path(other_field)) {
+            (None, None) => (quote(field), quote(other_field)),
+            (Some(_), None) => (
+                self.json_field(field),
+                format!("to_jsonb({})", quote(other_field)),
+            ),
+            (None, Some(_)) => (
+                format!("to_jsonb({})", quote(field)),
+                self.json_field(other_field),
+            ),
+            (Some(_), Some(_)) => (self.json_field(field), self.json_field(other_field)),
+        }
+    }
+
+    /// Decodes a field of a row to JSON following the path of a nested field.
+    fn decode_field(&self, row: &PgRow, field: &str) -> Result<serde_json::Value> {
+        match self.json_path(field) {
+            Some((column, path)) => {
+                let mut value = decode_json(row, column)?;
+                for key in path {
+                    value = value.get(key).cloned().unwrap_or(serde_json::Value::Null);
+                }
+                Ok(value)
+            }
+            None => decode_json(row, column_name(field)),
+        }
+    }
+
+    fn apply_filter(&self, builder: &mut QueryBuilder<Postgres>, filter: &F) -> Result<()> {
+        self.allowed_fields.check_filter(filter)?;
         builder.push(" where ");
         self.add_condition(builder, filter);
+        Ok(())
     }
 
     fn add_condition(&self, builder: &mut QueryBuilder<Postgres>, filter: &F) {
@@ -231,37 +454,138 @@ impl<T> PgRepo<T> {
                 builder.push(")");
             }
             F::IsNone(field) => {
-                builder.push(field).push(" is null");
+                builder.push(self.typed_field(field, None)).push(" is null");
             }
             F::Value { field, op } => match op {
                 Op::StrEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(val.clone());
                 }
                 Op::StrNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(val.clone());
                 }
                 Op::StrContains(val) => {
                     builder
-                        .push(field)
+                        .push(self.typed_field(field, Some(op)))
                         .push(" like '%' || ")
+                        .push_bind(escape_like(val))
+                        .push(" || '%' ");
+                }
+                Op::StrLike(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" like ")
+                        .push_bind(val.clone());
+                }
+                Op::StrIEq(val) => {
+                    builder
+                        .push("lower(")
+                        .push(self.typed_field(field, Some(op)))
+                        .push(") = lower(")
                         .push_bind(val.clone())
+                        .push(")");
+                }
+                Op::StrIContains(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" ilike '%' || ")
+                        .push_bind(escape_like(val))
                         .push(" || '%' ");
                 }
+                Op::StrIStartsWith(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" ilike ")
+                        .push_bind(escape_like(val))
+                        .push(" || '%' ");
+                }
+                Op::StrIEndsWith(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" ilike '%' || ")
+                        .push_bind(escape_like(val));
+                }
+                Op::StrMatches(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" ~ ")
+                        .push_bind(val.clone());
+                }
+                Op::StrIMatches(val) => {
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" ~* ")
+                        .push_bind(val.clone());
+                }
                 Op::StrStartsWith(val) => {
                     builder
-                        .push(field)
+                        .push(self.typed_field(field, Some(op)))
                         .push(" like ")
-                        .push_bind(val.clone())
+                        .push_bind(escape_like(val))
                         .push(" || '%' ");
                 }
                 Op::StrEndsWith(val) => {
                     builder
-                        .push(field)
+                        .push(self.typed_field(field, Some(op)))
                         .push(" like '%' || ")
-                        .push_bind(val.clone());
+                        .push_bind(escape_like(val));
+                }
+                Op::StrArrayHas(val) => {
+                    self.add_array_item_condition(builder, field, op, val.clone());
+                }
+                Op::StrArrayHasAll(values) => {
+                    self.add_array_condition(builder, field, op, " @> ", values.clone());
+                }
+                Op::StrArrayOverlaps(values) => {
+                    self.add_array_condition(builder, field, op, " && ", values.clone());
+                }
+                Op::IntArrayHas(val) => {
+                    self.add_array_item_condition(builder, field, op, *val);
+                }
+                Op::IntArrayHasAll(values) => {
+                    self.add_array_condition(builder, field, op, " @> ", values.clone());
+                }
+                Op::IntArrayOverlaps(values) => {
+                    self.add_array_condition(builder, field, op, " && ", values.clone());
+                }
+                Op::UuidArrayHas(val) => {
+                    self.add_array_item_condition(builder, field, op, *val);
+                }
+                Op::UuidArrayHasAll(values) => {
+                    self.add_array_condition(builder, field, op, " @> ", values.clone());
+                }
+                Op::UuidArrayOverlaps(values) => {
+                    self.add_array_condition(builder, field, op, " && ", values.clone());
+                }
+                Op::JsonContains(val) => {
+                    builder
+                        .push(self.json_field(field))
+                        .push(" @> ")
+                        .push_bind(Json(val.clone()));
+                }
+                Op::JsonHasKey(key) => {
+                    builder
+                        .push(self.json_field(field))
+                        .push(" ? ")
+                        .push_bind(key.clone());
+                }
+                Op::JsonPathEq(path, val) => {
+                    builder
+                        .push(self.json_field(field))
+                        .push(" #> ")
+                        .push_bind(path.clone())
+                        .push(" = ")
+                        .push_bind(Json(val.clone()));
                 }
                 Op::StrIn(values) => {
-                    builder.push(field).push(" in (");
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" in (");
                     let mut sep = builder.separated(", ");
                     for v in values {
                         sep.push_bind(v.clone());
@@ -269,117 +593,263 @@ impl<T> PgRepo<T> {
                     builder.push(")");
                 }
                 Op::IntEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::IntNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::IntLt(val) => {
-                    builder.push(field).push(" < ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" < ")
+                        .push_bind(*val);
                 }
                 Op::IntGt(val) => {
-                    builder.push(field).push(" > ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" > ")
+                        .push_bind(*val);
                 }
                 Op::IntLte(val) => {
-                    builder.push(field).push(" <= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" <= ")
+                        .push_bind(*val);
                 }
                 Op::IntGte(val) => {
-                    builder.push(field).push(" >= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" >= ")
+                        .push_bind(*val);
                 }
                 Op::IntBetween(x, y) => {
                     builder
-                        .push(field)
+                        .push(self.typed_field(field, Some(op)))
                         .push(" between ")
-                        .push_bind(x.clone())
+                        .push_bind(*x)
                         .push(" and ")
-                        .push_bind(y.clone());
+                        .push_bind(*y);
                 }
                 Op::IntIn(values) => {
-                    builder.push(field).push(" in (");
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" in (");
                     let mut sep = builder.separated(", ");
                     for v in values {
-                        sep.push_bind(v.clone());
+                        sep.push_bind(*v);
                     }
                     builder.push(")");
                 }
                 Op::BoolEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::BoolNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::FloatEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::FloatNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::FloatLt(val) => {
-                    builder.push(field).push(" < ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" < ")
+                        .push_bind(*val);
                 }
                 Op::FloatGt(val) => {
-                    builder.push(field).push(" > ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" > ")
+                        .push_bind(*val);
                 }
                 Op::FloatLte(val) => {
-                    builder.push(field).push(" <= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" <= ")
+                        .push_bind(*val);
                 }
                 Op::FloatGte(val) => {
-                    builder.push(field).push(" >= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" >= ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeLt(val) => {
-                    builder.push(field).push(" < ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" < ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeGt(val) => {
-                    builder.push(field).push(" > ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" > ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeLte(val) => {
-                    builder.push(field).push(" <= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" <= ")
+                        .push_bind(*val);
                 }
                 Op::DateTimeGte(val) => {
-                    builder.push(field).push(" >= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" >= ")
+                        .push_bind(*val);
                 }
                 Op::DecimalEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::DecimalNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::DecimalLt(val) => {
-                    builder.push(field).push(" < ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" < ")
+                        .push_bind(*val);
                 }
                 Op::DecimalGt(val) => {
-                    builder.push(field).push(" > ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" > ")
+                        .push_bind(*val);
                 }
                 Op::DecimalLte(val) => {
-                    builder.push(field).push(" <= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" <= ")
+                        .push_bind(*val);
                 }
                 Op::DecimalGte(val) => {
-                    builder.push(field).push(" >= ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" >= ")
+                        .push_bind(*val);
                 }
                 Op::UuidEq(val) => {
-                    builder.push(field).push(" = ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" = ")
+                        .push_bind(*val);
                 }
                 Op::UuidNe(val) => {
-                    builder.push(field).push(" != ").push_bind(val.clone());
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" != ")
+                        .push_bind(*val);
                 }
                 Op::UuidIn(values) => {
-                    builder.push(field).push(" in (");
+                    builder
+                        .push(self.typed_field(field, Some(op)))
+                        .push(" in (");
                     let mut sep = builder.separated(", ");
                     for v in values {
-                        sep.push_bind(v.clone());
+                        sep.push_bind(*v);
                     }
                     builder.push(")");
                 }
+                Op::FieldEq(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" = ").push(right);
+                }
+                Op::FieldNe(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" != ").push(right);
+                }
+                Op::FieldLt(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" < ").push(right);
+                }
+                Op::FieldGt(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" > ").push(right);
+                }
+                Op::FieldLte(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" <= ").push(right);
+                }
+                Op::FieldGte(other_field) => {
+                    let (left, right) = self.field_pair(field, other_field);
+                    builder.push(left).push(" >= ").push(right);
+                }
             },
         }
     }
 
+    /// Adds a condition that compares an array field with an array of values.
+    /// A single value is passed as an array, so all the conditions can use an index.
+    fn add_array_condition<'a, A>(
+        &self,
+        builder: &mut QueryBuilder<'a, Postgres>,
+        field: &str,
+        op: &Op,
+        operator: &str,
+        values: A,
+    ) where
+        A: 'a + Encode<'a, Postgres> + Send + Type<Postgres>,
+    {
+        builder
+            .push(self.typed_field(field, Some(op)))
+            .push(operator)
+            .push_bind(values);
+    }
+
+    fn add_array_item_condition<'a, V>(
+        &self,
+        builder: &mut QueryBuilder<'a, Postgres>,
+        field: &str,
+        op: &Op,
+        value: V,
+    ) where
+        V: 'a + Encode<'a, Postgres> + Send + Type<Postgres>,
+    {
+        builder
+            .push_bind(value)
+            .push(" = any(")
+            .push(self.typed_field(field, Some(op)))
+            .push(")");
+    }
+
     async fn get_via(
         &self,
         conn: &mut PgConnection,
@@ -387,7 +857,7 @@ impl<T> PgRepo<T> {
         for_update: bool,
     ) -> Result<Option<T>> {
         let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(self.query.clone());
-        self.apply_filter(&mut builder, filter);
+        self.apply_filter(&mut builder, filter)?;
 
         if for_update {
             builder.push(" for update");
@@ -399,128 +869,691 @@ impl<T> PgRepo<T> {
         match result {
             Ok(row) => Ok(Some((self.load)(&row))),
             Err(sqlx::Error::RowNotFound) => Ok(None),
-            Err(err) => bail!(err),
+            Err(err) => Err(err.into()),
         }
     }
 
-    async fn get_many_via(&self, conn: &mut PgConnection, query: &Query) -> Result<Vec<T>> {
+    async fn get_one_via(&self, conn: &mut PgConnection, filter: &F) -> Result<T> {
         let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(self.query.clone());
+        self.apply_filter(&mut builder, filter)?;
+        // Two rows are enough to find out that the filter is ambiguous.
+        builder.push(" limit 2");
 
-        if let Some(filter) = &query.filter {
-            self.apply_filter(&mut builder, &filter);
+        let query = builder.build();
+        let rows = query.fetch_all(&mut *conn).await?;
+
+        match &rows[..] {
+            [] => Err(Error::NotFound),
+            [row] => Ok((self.load)(row)),
+            _ => Err(Error::MultipleRows),
         }
+    }
 
-        if let Some(order) = &query.order {
-            builder.push(" order by ");
-            for (n, order_item) in order.iter().enumerate() {
-                if n > 0 {
-                    builder.push(", ");
+    fn apply_order(
+        builder: &mut QueryBuilder<Postgres>,
+        order: &[Order],
+        to_sql: impl Fn(&str) -> String,
+    ) {
+        builder.push(" order by ");
+        for (n, order_item) in order.iter().enumerate() {
+            if n > 0 {
+                builder.push(", ");
+            }
+            match order_item {
+                Order::Asc(field) => {
+                    builder.push(to_sql(field)).push(" asc");
                 }
-                match order_item {
-                    Order::Asc(field) => {
-                        builder.push(field).push(" asc");
-                    }
-                    Order::Desc(field) => {
-                        builder.push(field).push(" desc");
-                    }
+                Order::Desc(field) => {
+                    builder.push(to_sql(field)).push(" desc");
                 }
             }
         }
+    }
+
+    fn apply_order_and_limits(&self, builder: &mut QueryBuilder<Postgres>, query: &Query) {
+        if let Some(order) = &query.order {
+            Self::apply_order(builder, order, |field| self.json_field(field));
+        }
 
+        // Values that don't fit in a bigint are clamped instead of wrapping to negative ones.
         if let Some(limit) = query.limit {
-            builder.push(" limit ").push_bind(limit as i64);
+            builder
+                .push(" limit ")
+                .push_bind(i64::try_from(limit).unwrap_or(i64::MAX));
         }
 
         if let Some(offset) = query.offset {
-            builder.push(" offset ").push_bind(offset as i64);
+            builder
+                .push(" offset ")
+                .push_bind(i64::try_from(offset).unwrap_or(i64::MAX));
         }
+    }
 
-        let query = builder.build();
-        let result = query.fetch_all(&mut *conn).await;
-
-        match result {
-            Ok(rows) => Ok(rows.iter().map(self.load).collect()),
-            Err(err) => bail!(err),
+    /// Returns an expression that selects a field from the results of the query
+    /// wrapped in a subquery.
+    fn result_field(&self, field: &str) -> String {
+        match self.json_path(field) {
+            Some(_) => self.json_field(field),
+            None => quote(column_name(field)),
         }
     }
 
-    async fn update_via(&self, conn: &mut PgConnection, filter: &F, entity: &T) -> Result<()> {
-        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("update ");
-        builder.push(&self.table);
-        builder.push(" set ");
-
-        let data = (self.dump)(entity);
+    /// Returns names of the types of the order fields in the results of the query.
+    /// Nested fields of `jsonb` columns don't have types, since they are compared as `jsonb`.
+    async fn keyset_types(
+        &self,
+        conn: &mut PgConnection,
+        order: &[Order],
+    ) -> Result<Vec<Option<String>>> {
+        let column_types = self
+            .column_types
+            .get_or_try_init(|| async {
+                let describe = (&mut *conn).describe(&self.query).await?;
+                Ok::<_, Error>(
+                    describe
+                        .columns()
+                        .iter()
+                        .map(|column| {
+                            (
+                                column.name().to_string(),
+                                column.type_info().name().to_string(),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+            .await?;
+
+        order
+            .iter()
+            .map(|order_item| {
+                let field = match order_item {
+                    Order::Asc(field) | Order::Desc(field) => field,
+                };
+                if self.json_path(field).is_some() {
+                    return Ok(None);
+                }
+                column_types
+                    .get(column_name(field))
+                    .map(|column_type| Some(column_type.clone()))
+                    .ok_or_else(|| Error::UnknownField(field.to_string()))
+            })
+            .collect()
+    }
 
-        for (n, (key, value)) in data.iter().enumerate() {
-            if n != 0 {
-                builder.push(", ");
+    /// Adds a condition that selects rows following given values of the order fields.
+    fn add_keyset_condition(
+        &self,
+        builder: &mut QueryBuilder<Postgres>,
+        order: &[Order],
+        values: &[serde_json::Value],
+        types: &[Option<String>],
+    ) -> Result<()> {
+        let fields: Vec<(&String, &str)> = order
+            .iter()
+            .map(|order_item| match order_item {
+                Order::Asc(field) => (field, " > "),
+                Order::Desc(field) => (field, " < "),
+            })
+            .collect();
+
+        // A row comparison can use an index, but it works
+        // only if all the fields are sorted in the same direction.
+        if fields.iter().all(|(_, op)| *op == fields[0].1) {
+            builder.push("(");
+            let mut separated = builder.separated(", ");
+            for (field, _) in &fields {
+                separated.push(self.json_field(field));
             }
-            builder.push(key).push(" = ");
-            value.push_to(&mut builder);
+            builder.push(")").push(fields[0].1).push("(");
+            for (n, value) in values.iter().enumerate() {
+                if n > 0 {
+                    builder.push(", ");
+                }
+                push_cursor_value(builder, value, types[n].as_deref())?;
+            }
+            builder.push(")");
+            return Ok(());
         }
 
-        self.apply_filter(&mut builder, filter);
-        let query = builder.build();
-        query.execute(&mut *conn).await?;
-
-        if let Some(after_update) = self.after_update_hook {
-            for q in after_update(entity) {
-                q.execute(&mut *conn).await?;
+        builder.push("(");
+        for (n, (field, op)) in fields.iter().enumerate() {
+            if n > 0 {
+                builder.push(" or ");
+            }
+            builder.push("(");
+            for (m, (prev_field, _)) in fields[..n].iter().enumerate() {
+                builder.push(self.json_field(prev_field)).push(" = ");
+                push_cursor_value(builder, &values[m], types[m].as_deref())?;
+                builder.push(" and ");
             }
+            builder.push(self.json_field(field)).push(op);
+            push_cursor_value(builder, &values[n], types[n].as_deref())?;
+            builder.push(")");
         }
-
+        builder.push(")");
         Ok(())
     }
 
-    async fn delete_via(&self, conn: &mut PgConnection, filter: &F) -> Result<()> {
-        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("delete from ");
-        builder.push(&self.table);
-        self.apply_filter(&mut builder, filter);
-        let query = builder.build();
-        query.execute(&mut *conn).await?;
-        Ok(())
+    async fn select_query(
+        &self,
+        conn: &mut PgConnection,
+        query: &Query,
+    ) -> Result<QueryBuilder<'static, Postgres>> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("");
+        self.push_select(conn, &mut builder, query).await?;
+        Ok(builder)
     }
 
-    async fn add_via(&self, conn: &mut PgConnection, entity: &T) -> Result<()> {
-        let data = (self.dump)(entity);
-        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("insert into ");
-        builder.push(&self.table);
-        builder.push(" (");
-        let mut separated = builder.separated(", ");
-        for key in data.keys() {
-            separated.push(key);
+    /// Builds a query that selects only given fields.
+    async fn projection_query(
+        &self,
+        conn: &mut PgConnection,
+        query: &Query,
+        fields: &[&str],
+    ) -> Result<QueryBuilder<'static, Postgres>> {
+        if fields.is_empty() {
+            return Err(Error::InvalidQuery("No fields to select".to_string()));
         }
-        builder.push(") values (");
-        for (n, val) in data.values().enumerate() {
-            if n > 0 {
-                builder.push(", ");
-            }
-            val.push_to(&mut builder);
+        self.allowed_fields.check_fields(fields.iter().copied())?;
+
+        // The filter and the limits are applied inside the subquery,
+        // where they can refer to fields with table prefixes.
+        // Nested fields are named after their paths, like in JsonRepo,
+        // and prefixed fields keep their prefixes, which the subquery hides.
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|field| match self.json_path(field) {
+                Some(_) => format!("{} as {}", self.json_field(field), quote_name(field)),
+                None if column_name(field) != *field => {
+                    format!("{} as {}", quote(column_name(field)), quote_name(field))
+                }
+                None => quote(field),
+            })
+            .collect();
+        let mut builder: QueryBuilder<Postgres> =
+            QueryBuilder::new(format!("select {} from (", fields.join(", ")));
+        self.push_select(conn, &mut builder, query).await?;
+        builder.push(") as q");
+
+        // Rows of a subquery aren't guaranteed to keep their order.
+        if let Some(order) = &query.order {
+            Self::apply_order(&mut builder, order, |field| self.result_field(field));
         }
-        builder.push(")");
-        let query = builder.build();
-        query.execute(&mut *conn).await?;
+        Ok(builder)
+    }
 
-        if let Some(after_add) = self.after_add_hook {
-            for q in after_add(entity) {
-                q.execute(&mut *conn).await?;
-            }
+    /// Pushes the query filtered, ordered and limited according to a given [Query].
+    async fn push_select(
+        &self,
+        conn: &mut PgConnection,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        query: &Query,
+    ) -> Result<()> {
+        builder.push(self.query.clone());
+
+        if let Some(order) = &query.order {
+            self.allowed_fields.check_order(order)?;
+        }
+        if let Some(filter) = &query.filter {
+            self.apply_filter(builder, filter)?;
+        }
+
+        if let Some((order, values)) = query.keyset()? {
+            builder.push(if query.filter.is_some() {
+                " and "
+            } else {
+                " where "
+            });
+            let types = self.keyset_types(conn, order).await?;
+            self.add_keyset_condition(builder, order, values, &types)?;
         }
 
+        self.apply_order_and_limits(builder, query);
         Ok(())
     }
 
-    async fn exists_via(&self, conn: &mut PgConnection, filter: &F) -> Result<bool> {
+    /// Builds a query that also selects the number of entities
+    /// matching the filter in the last column `orlok_total`.
+    /// Rows of the page have `true` in the `orlok_found` column. If the page is empty,
+    /// the query returns one row with the total and nulls in the other columns.
+    async fn page_query(
+        &self,
+        conn: &mut PgConnection,
+        query: &Query,
+    ) -> Result<QueryBuilder<'static, Postgres>> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "select p.*, t.orlok_total from (select count(*) as orlok_total from (",
+        );
+        builder.push(self.query.clone());
+
+        if let Some(filter) = &query.filter {
+            self.apply_filter(&mut builder, filter)?;
+        }
+
+        // The total is counted before the cursor condition and the limit are applied,
+        // and the left join keeps it even if no entities follow the cursor.
+        builder.push(") as c) as t left join (select *, true as orlok_found from (");
+        self.push_select(conn, &mut builder, query).await?;
+        builder.push(") as q) as p on true");
+
+        if let Some(order) = &query.order {
+            Self::apply_order(&mut builder, order, |field| self.result_field(field));
+        }
+        Ok(builder)
+    }
+
+    async fn get_many_via(&self, conn: &mut PgConnection, query: &Query) -> Result<Vec<T>> {
+        let mut builder = self.select_query(conn, query).await?;
+        let query = builder.build();
+        let result = query.fetch_all(&mut *conn).await;
+
+        match result {
+            Ok(rows) => Ok(rows.iter().map(self.load).collect()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get_page_via(&self, conn: &mut PgConnection, query: &Query) -> Result<Page<T>> {
+        // One more row is selected to find out if there is a next page.
+        let mut page_query = query.clone();
+        page_query.limit = query.limit.map(|limit| limit.saturating_add(1));
+        let mut builder = self.page_query(conn, &page_query).await?;
+        let mut rows = builder.build().fetch_all(&mut *conn).await?;
+
+        let total = match rows.first() {
+            Some(row) => row.try_get("orlok_total")?,
+            None => 0,
+        };
+        // A row without an entity means that the page is empty.
+        if rows.len() == 1 && rows[0].try_get::<Option<bool>, _>("orlok_found")?.is_none() {
+            rows.clear();
+        }
+        let limit = query.limit.unwrap_or(rows.len());
+
+        let next_cursor = match &query.order {
+            Some(order) if limit > 0 && rows.len() > limit => {
+                let row = &rows[limit - 1];
+                let values = order
+                    .iter()
+                    .map(|order_item| match order_item {
+                        Order::Asc(field) | Order::Desc(field) => self.decode_field(row, field),
+                    })
+                    .collect::<Result<_>>()?;
+                Some(Cursor::new(values))
+            }
+            _ => None,
+        };
+
+        Ok(Page {
+            items: rows.iter().take(limit).map(self.load).collect(),
+            next_cursor,
+            total,
+        })
+    }
+
+    /// Finds entities and loads only given fields of them with a given function.
+    /// It's a lighter alternative to [Repo::project] for [PgRepo].
+    pub async fn project_with<R>(
+        &self,
+        db: &PgDb<'_>,
+        query: &Query,
+        fields: &[&str],
+        load: fn(&PgRow) -> R,
+    ) -> Result<Vec<R>> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.project_via(&mut conn, query, fields, load).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.project_via(&mut t, query, fields, load).await
+            }
+        }
+    }
+
+    async fn project_via<R>(
+        &self,
+        conn: &mut PgConnection,
+        query: &Query,
+        fields: &[&str],
+        load: impl Fn(&PgRow) -> R,
+    ) -> Result<Vec<R>> {
+        let mut builder = self.projection_query(conn, query, fields).await?;
+        let rows = builder.build().fetch_all(&mut *conn).await?;
+        Ok(rows.iter().map(load).collect())
+    }
+
+    async fn stream_to(
+        &self,
+        db: &PgDb<'_>,
+        query: &Query,
+        sender: &mpsc::Sender<Result<T>>,
+    ) -> Result<()> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.stream_via(&mut conn, query, sender).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.stream_via(&mut t, query, sender).await
+            }
+        }
+    }
+
+    async fn stream_via(
+        &self,
+        conn: &mut PgConnection,
+        query: &Query,
+        sender: &mpsc::Sender<Result<T>>,
+    ) -> Result<()> {
+        let mut builder = self.select_query(conn, query).await?;
+        let query = builder.build();
+        let mut rows = query.fetch(&mut *conn);
+
+        while let Some(row) = rows.try_next().await? {
+            if sender.send(Ok((self.load)(&row))).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_via(&self, conn: &mut PgConnection, filter: &F, entity: &T) -> Result<u64> {
+        let count = self.update_rows(conn, filter, entity).await?;
+
+        if count > 0 {
+            self.run_after_update(conn, entity).await?;
+        }
+
+        Ok(count)
+    }
+
+    async fn update_one_via(&self, conn: &mut PgConnection, filter: &F, entity: &T) -> Result<()> {
+        let mut tx = conn.begin().await?;
+
+        match self.update_rows(&mut tx, filter, entity).await? {
+            0 => Err(Error::NotFound),
+            1 => {
+                self.run_after_update(&mut tx, entity).await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            _ => {
+                tx.rollback().await?;
+                Err(Error::MultipleRows)
+            }
+        }
+    }
+
+    async fn run_after_update(&self, conn: &mut PgConnection, entity: &T) -> Result<()> {
+        if let Some(after_update) = self.after_update_hook {
+            for q in after_update(entity) {
+                q.execute(&mut *conn).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_rows(&self, conn: &mut PgConnection, filter: &F, entity: &T) -> Result<u64> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("update ");
+        builder.push(&self.table);
+        builder.push(" set ");
+
+        let data = (self.dump)(entity);
+
+        for (n, (key, value)) in data.iter().enumerate() {
+            if n != 0 {
+                builder.push(", ");
+            }
+            builder.push(quote(key)).push(" = ");
+            value.push_to(&mut builder);
+        }
+
+        self.apply_filter(&mut builder, filter)?;
+        let query = builder.build();
+        let count = query.execute(&mut *conn).await?.rows_affected();
+        Ok(count)
+    }
+
+    async fn delete_via(&self, conn: &mut PgConnection, filter: &F) -> Result<u64> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("delete from ");
+        builder.push(&self.table);
+        self.apply_filter(&mut builder, filter)?;
+        let query = builder.build();
+        let count = query.execute(&mut *conn).await?.rows_affected();
+        Ok(count)
+    }
+
+    async fn add_via(&self, conn: &mut PgConnection, entity: &T) -> Result<()> {
+        let data = (self.dump)(entity);
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("insert into ");
+        builder.push(&self.table);
+        builder.push(" (");
+        let mut separated = builder.separated(", ");
+        for key in data.keys() {
+            separated.push(quote(key));
+        }
+        builder.push(") values (");
+        for (n, val) in data.values().enumerate() {
+            if n > 0 {
+                builder.push(", ");
+            }
+            val.push_to(&mut builder);
+        }
+        builder.push(")");
+        let query = builder.build();
+        query.execute(&mut *conn).await?;
+
+        if let Some(after_add) = self.after_add_hook {
+            for q in after_add(entity) {
+                q.execute(&mut *conn).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dumps an entity of a batch and checks that it has the same fields
+    /// as the first one, since all the rows of a batch have the same columns.
+    fn dump_with_keys(&self, entity: &T, keys: &[String]) -> Result<HashMap<String, Value>> {
+        let data = (self.dump)(entity);
+        if data.len() != keys.len() || !keys.iter().all(|key| data.contains_key(key)) {
+            return Err(Error::Encode(
+                "All entities of a batch must have the same fields".to_string(),
+            ));
+        }
+        Ok(data)
+    }
+
+    async fn add_many_via(&self, conn: &mut PgConnection, entities: &[T]) -> Result<()> {
+        if entities.is_empty() {
+            return Ok(());
+        }
+
+        // Columns are taken from the first entity, so every entity
+        // is inserted with the same column order.
+        let keys: Vec<String> = (self.dump)(&entities[0]).into_keys().collect();
+        let chunk_size = (MAX_BINDS / keys.len().max(1)).max(1);
+        let mut tx = conn.begin().await?;
+
+        for chunk in entities.chunks(chunk_size) {
+            let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("insert into ");
+            builder.push(&self.table);
+            builder.push(" (");
+            let mut separated = builder.separated(", ");
+            for key in &keys {
+                separated.push(quote(key));
+            }
+            builder.push(") values ");
+
+            for (n, entity) in chunk.iter().enumerate() {
+                let mut data = self.dump_with_keys(entity, &keys)?;
+                if n > 0 {
+                    builder.push(", ");
+                }
+                builder.push("(");
+                for (n, key) in keys.iter().enumerate() {
+                    if n > 0 {
+                        builder.push(", ");
+                    }
+                    data.remove(key)
+                        .unwrap_or(Value::Null)
+                        .push_to(&mut builder);
+                }
+                builder.push(")");
+            }
+
+            let query = builder.build();
+            query.execute(&mut *tx).await?;
+
+            if let Some(after_add) = self.after_add_hook {
+                for entity in chunk {
+                    for q in after_add(entity) {
+                        q.execute(&mut *tx).await?;
+                    }
+                }
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn copy_in_via(&self, conn: &mut PgConnection, entities: &[T]) -> Result<u64> {
+        if entities.is_empty() {
+            return Ok(0);
+        }
+
+        let keys: Vec<String> = (self.dump)(&entities[0]).into_keys().collect();
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("copy ");
+        builder.push(&self.table);
+        builder.push(" (");
+        let mut separated = builder.separated(", ");
+        for key in &keys {
+            separated.push(quote(key));
+        }
+        builder.push(") from stdin");
+
+        let mut tx = conn.begin().await?;
+        let mut copy = tx.copy_in_raw(builder.sql()).await?;
+
+        for chunk in entities.chunks(COPY_CHUNK_SIZE) {
+            let mut buf = String::new();
+            for entity in chunk {
+                let mut data = match self.dump_with_keys(entity, &keys) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        copy.abort(err.to_string()).await?;
+                        return Err(err);
+                    }
+                };
+                for (n, key) in keys.iter().enumerate() {
+                    if n > 0 {
+                        buf.push('\t');
+                    }
+                    data.remove(key)
+                        .unwrap_or(Value::Null)
+                        .write_copy_text(&mut buf);
+                }
+                buf.push('\n');
+            }
+            copy.send(buf.into_bytes()).await?;
+        }
+
+        let count = copy.finish().await?;
+
+        if let Some(after_add) = self.after_add_hook {
+            for entity in entities {
+                for q in after_add(entity) {
+                    q.execute(&mut *tx).await?;
+                }
+            }
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    async fn upsert_via(
+        &self,
+        conn: &mut PgConnection,
+        entity: &T,
+        conflict_target: &[&str],
+    ) -> Result<Upserted> {
+        if conflict_target.is_empty() {
+            return Err(Error::InvalidQuery("No conflict target".to_string()));
+        }
+        let data = (self.dump)(entity);
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("insert into ");
+        builder.push(&self.table);
+        builder.push(" (");
+        let mut separated = builder.separated(", ");
+        for key in data.keys() {
+            separated.push(quote(key));
+        }
+        builder.push(") values (");
+        for (n, val) in data.values().enumerate() {
+            if n > 0 {
+                builder.push(", ");
+            }
+            val.push_to(&mut builder);
+        }
+        builder.push(") on conflict (");
+        let mut separated = builder.separated(", ");
+        for field in conflict_target {
+            separated.push(quote(field));
+        }
+        builder.push(") do update set ");
+
+        // At least one column must be updated, otherwise
+        // the query doesn't return the conflicting row.
+        let mut keys: Vec<&String> = data
+            .keys()
+            .filter(|key| !conflict_target.contains(&key.as_str()))
+            .collect();
+        if keys.is_empty() {
+            keys = data.keys().collect();
+        }
+        let mut separated = builder.separated(", ");
+        for key in keys {
+            separated.push(format!("{0} = excluded.{0}", quote(key)));
+        }
+
+        // A new row has no deleting transaction.
+        builder.push(" returning (xmax = 0) as inserted");
+        let query = builder.build();
+        let row = query.fetch_one(&mut *conn).await?;
+
+        let (upserted, hook) = if row.get("inserted") {
+            (Upserted::Inserted, self.after_add_hook)
+        } else {
+            (Upserted::Updated, self.after_update_hook)
+        };
+
+        if let Some(hook) = hook {
+            for q in hook(entity) {
+                q.execute(&mut *conn).await?;
+            }
+        }
+
+        Ok(upserted)
+    }
+
+    async fn exists_via(&self, conn: &mut PgConnection, filter: &F) -> Result<bool> {
         let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("select exists (");
         builder.push(self.query.clone());
-        self.apply_filter(&mut builder, filter);
+        self.apply_filter(&mut builder, filter)?;
         builder.push(") as result");
         let query = builder.build();
         let result = query.fetch_one(&mut *conn).await;
 
         match result {
             Ok(row) => Ok(row.get("result")),
-            Err(err) => bail!(err),
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -528,17 +1561,80 @@ impl<T> PgRepo<T> {
         let mut builder: QueryBuilder<Postgres> =
             QueryBuilder::new("select count(1) as result from (");
         builder.push(self.query.clone());
-        self.apply_filter(&mut builder, filter);
+        self.apply_filter(&mut builder, filter)?;
         builder.push(") as q");
         let query = builder.build();
         let result = query.fetch_one(&mut *conn).await;
 
         match result {
             Ok(row) => Ok(row.get("result")),
-            Err(err) => bail!(err),
+            Err(err) => Err(err.into()),
         }
     }
 
+    async fn aggregate_via<R>(
+        &self,
+        conn: &mut PgConnection,
+        filter: Option<&F>,
+        group_by: &[&str],
+        aggs: &[Agg],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned,
+    {
+        check_aggregation(group_by, aggs)?;
+        self.allowed_fields.check_fields(
+            group_by
+                .iter()
+                .copied()
+                .chain(aggs.iter().filter_map(Agg::field)),
+        )?;
+
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new("select ");
+        let mut separated = builder.separated(", ");
+        for field in group_by {
+            separated.push(quote(field));
+        }
+        for agg in aggs {
+            let function = match agg {
+                Agg::Count => "count(*)".to_string(),
+                Agg::Sum(field) => format!("sum({})", quote(field)),
+                Agg::Avg(field) => format!("avg({})", quote(field)),
+                Agg::Min(field) => format!("min({})", quote(field)),
+                Agg::Max(field) => format!("max({})", quote(field)),
+            };
+            separated.push(format!("{} as {}", function, quote(&agg.alias())));
+        }
+
+        builder.push(" from (");
+        builder.push(self.query.clone());
+        if let Some(filter) = filter {
+            self.apply_filter(&mut builder, filter)?;
+        }
+        builder.push(") as q");
+
+        if !group_by.is_empty() {
+            let fields: Vec<String> = group_by.iter().map(|field| quote(field)).collect();
+            builder.push(" group by ").push(fields.join(", "));
+            // Null groups go first, like nulls in orders of JsonRepo.
+            let order: Vec<String> = fields
+                .iter()
+                .map(|field| format!("{} nulls first", field))
+                .collect();
+            builder.push(" order by ").push(order.join(", "));
+        }
+
+        let query = builder.build();
+        let rows = query.fetch_all(&mut *conn).await?;
+
+        rows.iter()
+            .map(|row| {
+                serde_json::from_value(row_to_json(row)?)
+                    .map_err(|err| Error::Decode(err.to_string()))
+            })
+            .collect()
+    }
+
     async fn count_all_via(&self, conn: &mut PgConnection) -> Result<i64> {
         let mut builder: QueryBuilder<Postgres> =
             QueryBuilder::new("select count(1) as result from (");
@@ -548,7 +1644,7 @@ impl<T> PgRepo<T> {
 
         match result {
             Ok(row) => Ok(row.get("result")),
-            Err(err) => bail!(err),
+            Err(err) => Err(err.into()),
         }
     }
 }
@@ -568,7 +1664,20 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.get_via(&mut *t, filter, false).await
+                self.get_via(&mut t, filter, false).await
+            }
+        }
+    }
+
+    async fn get_one<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<T> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.get_one_via(&mut conn, filter).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.get_one_via(&mut t, filter).await
             }
         }
     }
@@ -581,12 +1690,12 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.get_many_via(&mut *t, query).await
+                self.get_many_via(&mut t, query).await
             }
         }
     }
 
-    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()> {
+    async fn update<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<u64> {
         match db {
             PgDb::Pool(p) => {
                 let mut conn = p.acquire().await?;
@@ -594,12 +1703,25 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.update_via(&mut *t, filter, entity).await
+                self.update_via(&mut t, filter, entity).await
             }
         }
     }
 
-    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<()> {
+    async fn update_one<'a>(&self, db: &Self::Db<'a>, filter: &F, entity: &T) -> Result<()> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.update_one_via(&mut conn, filter, entity).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.update_one_via(&mut t, filter, entity).await
+            }
+        }
+    }
+
+    async fn delete<'a>(&self, db: &Self::Db<'a>, filter: &F) -> Result<u64> {
         match db {
             PgDb::Pool(p) => {
                 let mut c = p.acquire().await?;
@@ -612,6 +1734,64 @@ where
         }
     }
 
+    async fn project<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        query: &Query,
+        fields: &[&str],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send,
+    {
+        let load = |row: &PgRow| {
+            serde_json::from_value(row_to_json(row)?).map_err(|err| Error::Decode(err.to_string()))
+        };
+        let rows = match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.project_via(&mut conn, query, fields, load).await?
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.project_via(&mut t, query, fields, load).await?
+            }
+        };
+        rows.into_iter().collect()
+    }
+
+    async fn get_page<'a>(&self, db: &Self::Db<'a>, query: &Query) -> Result<Page<T>> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.get_page_via(&mut conn, query).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.get_page_via(&mut t, query).await
+            }
+        }
+    }
+
+    fn stream<'a, 'b>(
+        &'b self,
+        db: &'b Self::Db<'a>,
+        query: &'b Query,
+    ) -> BoxStream<'b, Result<T>> {
+        let (sender, mut receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+
+        // Rows are fetched by a future that is polled together with the stream,
+        // so it can borrow the connection without spawning a task.
+        let producer = async move {
+            if let Err(err) = self.stream_to(db, query, &sender).await {
+                let _ = sender.send(Err(err)).await;
+            }
+        };
+
+        let entities = stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        let producer = producer.into_stream().filter_map(|_| future::ready(None));
+        Box::pin(stream::select(entities, producer))
+    }
+
     async fn add<'a>(&self, db: &Self::Db<'a>, entity: &T) -> Result<()> {
         match db {
             PgDb::Pool(p) => {
@@ -620,7 +1800,38 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.add_via(&mut *t, entity).await
+                self.add_via(&mut t, entity).await
+            }
+        }
+    }
+
+    async fn add_many<'a>(&self, db: &Self::Db<'a>, entities: &[T]) -> Result<()> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.add_many_via(&mut conn, entities).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.add_many_via(&mut t, entities).await
+            }
+        }
+    }
+
+    async fn upsert<'a>(
+        &self,
+        db: &Self::Db<'a>,
+        entity: &T,
+        conflict_target: &[&str],
+    ) -> Result<Upserted> {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.upsert_via(&mut conn, entity, conflict_target).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.upsert_via(&mut t, entity, conflict_target).await
             }
         }
     }
@@ -633,7 +1844,7 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.exists_via(&mut *t, filter).await
+                self.exists_via(&mut t, filter).await
             }
         }
     }
@@ -646,7 +1857,7 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.count_via(&mut *t, filter).await
+                self.count_via(&mut t, filter).await
             }
         }
     }
@@ -659,7 +1870,29 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.count_all_via(&mut *t).await
+                self.count_all_via(&mut t).await
+            }
+        }
+    }
+
+    async fn aggregate<'a, R>(
+        &self,
+        db: &Self::Db<'a>,
+        filter: Option<&F>,
+        group_by: &[&str],
+        aggs: &[Agg],
+    ) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send,
+    {
+        match db {
+            PgDb::Pool(p) => {
+                let mut conn = p.acquire().await?;
+                self.aggregate_via(&mut conn, filter, group_by, aggs).await
+            }
+            PgDb::Transaction(t) => {
+                let mut t = t.write().await;
+                self.aggregate_via(&mut t, filter, group_by, aggs).await
             }
         }
     }
@@ -672,7 +1905,7 @@ where
             }
             PgDb::Transaction(t) => {
                 let mut t = t.write().await;
-                self.get_via(&mut *t, filter, true).await
+                self.get_via(&mut t, filter, true).await
             }
         }
     }
@@ -680,6 +1913,7 @@ where
 
 /// Wrapper around a pool of connections to PostgreSQL
 /// or a transaction.
+#[allow(clippy::large_enum_variant)]
 pub enum PgDb<'a> {
     Pool(PgPool),
     Transaction(RwLock<sqlx::Transaction<'a, Postgres>>),
@@ -693,31 +1927,264 @@ impl PgDb<'_> {
 
 #[async_trait]
 impl Db for PgDb<'_> {
-    async fn transaction<A, T>(&self, action: A) -> Result<T>
+    async fn transaction_with<A, T>(
+        &self,
+        options: TransactionOptions,
+        action: A,
+    ) -> anyhow::Result<T>
     where
-        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>> + Send,
+        A: for<'a> FnOnce(&'a Self) -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>
+            + Send,
         T: Send,
     {
-        let wrapped = RwLock::new(match self {
-            Self::Transaction(_) => todo!(),
-            Self::Pool(p) => p.begin().await?,
-        });
+        let pool = match self {
+            Self::Pool(p) => p,
+            Self::Transaction(t) => {
+                // PostgreSQL keeps savepoints with the same name on a stack
+                // and always uses the most recent one, so a single name
+                // is enough for any depth of nesting.
+                execute(t, "savepoint orlok_savepoint").await?;
+
+                if options.read_only {
+                    execute(t, "set transaction read only").await?;
+                }
+
+                return match action(self).await {
+                    Ok(res) => {
+                        execute(t, "release savepoint orlok_savepoint").await?;
+                        Ok(res)
+                    }
+                    Err(err) => {
+                        execute(t, "rollback to savepoint orlok_savepoint").await?;
+                        execute(t, "release savepoint orlok_savepoint").await?;
+                        anyhow::bail!(err)
+                    }
+                };
+            }
+        };
 
-        let tx = Self::Transaction(wrapped);
+        let tx = pool.begin().await.map_err(Error::from)?;
+        let mut modes = Vec::new();
+
+        match options.isolation_level {
+            Some(IsolationLevel::ReadCommitted) => modes.push("isolation level read committed"),
+            Some(IsolationLevel::RepeatableRead) => modes.push("isolation level repeatable read"),
+            Some(IsolationLevel::Serializable) => modes.push("isolation level serializable"),
+            None => {}
+        }
+
+        if options.read_only {
+            modes.push("read only");
+        }
+
+        if options.deferrable {
+            modes.push("deferrable");
+        }
+
+        let tx = RwLock::new(tx);
+
+        if !modes.is_empty() {
+            execute(&tx, &format!("set transaction {}", modes.join(", "))).await?;
+        }
+
+        let tx = Self::Transaction(tx);
 
         match action(&tx).await {
             Ok(res) => {
                 if let Self::Transaction(t) = tx {
-                    t.into_inner().commit().await?;
+                    t.into_inner().commit().await.map_err(Error::from)?;
                 }
                 Ok(res)
             }
             Err(err) => {
                 if let Self::Transaction(t) = tx {
-                    t.into_inner().rollback().await?;
+                    t.into_inner().rollback().await.map_err(Error::from)?;
                 }
-                bail!(err)
+                anyhow::bail!(err)
             }
         }
     }
+
+    fn is_retryable(err: &anyhow::Error) -> bool {
+        err.chain().any(|e| {
+            if let Some(e) = e.downcast_ref::<Error>() {
+                return e.is_retryable();
+            }
+
+            match e.downcast_ref::<sqlx::Error>() {
+                Some(sqlx::Error::Database(e)) => {
+                    matches!(e.code().as_deref(), Some("40001") | Some("40P01"))
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+async fn execute(transaction: &RwLock<sqlx::Transaction<'_, Postgres>>, sql: &str) -> Result<()> {
+    sqlx::query(sql)
+        .execute(&mut *transaction.write().await)
+        .await?;
+    Ok(())
+}
+
+/// Quotes a field name, so it can't be used to inject SQL.
+/// Each part of a dotted name is quoted separately.
+fn quote(field: &str) -> String {
+    field
+        .split('.')
+        .map(quote_name)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Returns the name of the column of a field in the results of the query.
+/// A field can have a table prefix, like `users.name`, to be filtered or ordered
+/// in the query itself, but the columns of its results are named without prefixes.
+fn column_name(field: &str) -> &str {
+    field.rsplit('.').next().unwrap_or(field)
+}
+
+/// Quotes a name as a whole, so it can contain dots.
+fn quote_name(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Escapes wildcards of a LIKE pattern, so it matches only itself.
+fn escape_like(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Binds a value of a cursor cast to the type of the column that it's compared with.
+/// A value compared with a nested field of a `jsonb` column is bound as `jsonb`.
+fn push_cursor_value(
+    builder: &mut QueryBuilder<Postgres>,
+    value: &serde_json::Value,
+    column_type: Option<&str>,
+) -> Result<()> {
+    let column_type = match column_type {
+        None | Some("JSONB") => {
+            builder.push_bind(Json(value.clone()));
+            return Ok(());
+        }
+        Some(column_type) => column_type,
+    };
+
+    let text = match value {
+        serde_json::Value::Bool(b) => b.to_string(),
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        _ => {
+            return Err(Error::InvalidQuery(format!(
+                "{} can't be used in a cursor",
+                value
+            )))
+        }
+    };
+    builder
+        .push("cast(")
+        .push_bind(text)
+        .push(" as ")
+        .push(column_type)
+        .push(")");
+    Ok(())
+}
+
+/// Quotes a string literal.
+fn quote_literal(text: &str) -> String {
+    format!("E'{}'", text.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Returns a type to which a nested field of a `jsonb` column
+/// must be cast to be compared with an argument of an operation.
+fn json_cast(op: &Op) -> Option<&'static str> {
+    match op {
+        Op::IntEq(_)
+        | Op::IntNe(_)
+        | Op::IntLt(_)
+        | Op::IntGt(_)
+        | Op::IntLte(_)
+        | Op::IntGte(_)
+        | Op::IntBetween(_, _)
+        | Op::IntIn(_) => Some("bigint"),
+        Op::FloatEq(_)
+        | Op::FloatNe(_)
+        | Op::FloatLt(_)
+        | Op::FloatGt(_)
+        | Op::FloatLte(_)
+        | Op::FloatGte(_) => Some("float8"),
+        Op::DecimalEq(_)
+        | Op::DecimalNe(_)
+        | Op::DecimalLt(_)
+        | Op::DecimalGt(_)
+        | Op::DecimalLte(_)
+        | Op::DecimalGte(_) => Some("numeric"),
+        Op::DateTimeEq(_)
+        | Op::DateTimeNe(_)
+        | Op::DateTimeLt(_)
+        | Op::DateTimeGt(_)
+        | Op::DateTimeLte(_)
+        | Op::DateTimeGte(_) => Some("timestamptz"),
+        Op::BoolEq(_) | Op::BoolNe(_) => Some("boolean"),
+        Op::UuidEq(_) | Op::UuidNe(_) | Op::UuidIn(_) => Some("uuid"),
+        _ => None,
+    }
+}
+
+/// Decodes a row to a JSON object.
+fn row_to_json(row: &PgRow) -> Result<serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for column in row.columns() {
+        object.insert(column.name().to_string(), decode_json(row, column.name())?);
+    }
+    Ok(object.into())
+}
+
+/// Decodes a column value to JSON.
+fn decode_json(row: &PgRow, column: &str) -> Result<serde_json::Value> {
+    let raw = row.try_get_raw(column)?;
+
+    if raw.is_null() {
+        return Ok(serde_json::Value::Null);
+    }
+
+    let type_name = raw.type_info().name().to_string();
+
+    Ok(match type_name.as_str() {
+        "TEXT" | "VARCHAR" | "BPCHAR" | "CHAR" | "NAME" => row.try_get::<String, _>(column)?.into(),
+        "INT2" => row.try_get::<i16, _>(column)?.into(),
+        "INT4" => row.try_get::<i32, _>(column)?.into(),
+        "INT8" => row.try_get::<i64, _>(column)?.into(),
+        "FLOAT4" => row.try_get::<f32, _>(column)?.into(),
+        "FLOAT8" => row.try_get::<f64, _>(column)?.into(),
+        "BOOL" => row.try_get::<bool, _>(column)?.into(),
+        "NUMERIC" => row.try_get::<Decimal, _>(column)?.to_string().into(),
+        "UUID" => row.try_get::<Uuid, _>(column)?.to_string().into(),
+        "TIMESTAMPTZ" => row
+            .try_get::<DateTime<Utc>, _>(column)?
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            .into(),
+        "TIMESTAMP" => row
+            .try_get::<NaiveDateTime, _>(column)?
+            .format("%Y-%m-%dT%H:%M:%S%.f")
+            .to_string()
+            .into(),
+        "DATE" => row.try_get::<NaiveDate, _>(column)?.to_string().into(),
+        "JSON" | "JSONB" => row.try_get::<serde_json::Value, _>(column)?,
+        "TEXT[]" => row.try_get::<Vec<String>, _>(column)?.into(),
+        "INT8[]" => row.try_get::<Vec<i64>, _>(column)?.into(),
+        "UUID[]" => row
+            .try_get::<Vec<Uuid>, _>(column)?
+            .iter()
+            .map(Uuid::to_string)
+            .collect(),
+        _ => {
+            return Err(Error::TypeMismatch(format!(
+                "Column {} of type {} can't be converted to JSON",
+                column, type_name
+            )))
+        }
+    })
 }
@@ -1,8 +1,14 @@
 //! Structs that can be used for building queries.
+use std::fmt;
+use std::str::FromStr;
+
 use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::error::Error;
+
 /// [Filter] operations.
 #[derive(Clone, Debug)]
 pub enum Op {
@@ -11,6 +17,13 @@ pub enum Op {
     StrStartsWith(String),
     StrEndsWith(String),
     StrContains(String),
+    StrLike(String),
+    StrIEq(String),
+    StrIContains(String),
+    StrIStartsWith(String),
+    StrIEndsWith(String),
+    StrMatches(String),
+    StrIMatches(String),
     StrIn(Vec<String>),
     IntEq(i64),
     IntNe(i64),
@@ -43,8 +56,46 @@ pub enum Op {
     UuidEq(Uuid),
     UuidNe(Uuid),
     UuidIn(Vec<Uuid>),
+    StrArrayHas(String),
+    StrArrayHasAll(Vec<String>),
+    StrArrayOverlaps(Vec<String>),
+    IntArrayHas(i64),
+    IntArrayHasAll(Vec<i64>),
+    IntArrayOverlaps(Vec<i64>),
+    UuidArrayHas(Uuid),
+    UuidArrayHasAll(Vec<Uuid>),
+    UuidArrayOverlaps(Vec<Uuid>),
+    JsonContains(serde_json::Value),
+    JsonHasKey(String),
+    JsonPathEq(Vec<String>, serde_json::Value),
+    FieldEq(String),
+    FieldNe(String),
+    FieldLt(String),
+    FieldGt(String),
+    FieldLte(String),
+    FieldGte(String),
+}
+
+impl Op {
+    /// Returns the name of the field that this operation compares with.
+    pub fn other_field(&self) -> Option<&str> {
+        match self {
+            Self::FieldEq(field)
+            | Self::FieldNe(field)
+            | Self::FieldLt(field)
+            | Self::FieldGt(field)
+            | Self::FieldLte(field)
+            | Self::FieldGte(field) => Some(field),
+            _ => None,
+        }
+    }
 }
 
+/// Reference to a field that can be used as an argument of a filter
+/// to compare two fields of an entity, for example `F::gt("a", Field("b"))`.
+#[derive(Clone, Debug)]
+pub struct Field<S = String>(pub S);
+
 /// Enum for filtering entities.
 #[derive(Clone, Debug)]
 pub enum Filter {
@@ -103,6 +154,36 @@ pub trait ContainsArg {
     fn to_op(self) -> Op;
 }
 
+/// Argument for the [Filter::like] method.
+pub trait LikeArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::has] method.
+pub trait HasArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::has_all] method.
+pub trait HasAllArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::overlaps] method.
+pub trait OverlapsArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::json_contains] method.
+pub trait JsonContainsArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::has_key] method.
+pub trait HasKeyArg {
+    fn to_op(self) -> Op;
+}
+
 /// Argument for the [Filter::starts_with] method.
 pub trait StartsWithArg {
     fn to_op(self) -> Op;
@@ -113,6 +194,36 @@ pub trait EndsWithArg {
     fn to_op(self) -> Op;
 }
 
+/// Argument for the [Filter::ieq] method.
+pub trait IEqArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::icontains] method.
+pub trait IContainsArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::istarts_with] method.
+pub trait IStartsWithArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::iends_with] method.
+pub trait IEndsWithArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::matches] method.
+pub trait MatchesArg {
+    fn to_op(self) -> Op;
+}
+
+/// Argument for the [Filter::imatches] method.
+pub trait IMatchesArg {
+    fn to_op(self) -> Op;
+}
+
 impl EqArg for i64 {
     fn to_op(self) -> Op {
         Op::IntEq(self)
@@ -179,6 +290,18 @@ impl ContainsArg for String {
     }
 }
 
+impl LikeArg for String {
+    fn to_op(self) -> Op {
+        Op::StrLike(self)
+    }
+}
+
+impl HasKeyArg for String {
+    fn to_op(self) -> Op {
+        Op::JsonHasKey(self)
+    }
+}
+
 impl StartsWithArg for String {
     fn to_op(self) -> Op {
         Op::StrStartsWith(self)
@@ -191,6 +314,42 @@ impl EndsWithArg for String {
     }
 }
 
+impl IEqArg for String {
+    fn to_op(self) -> Op {
+        Op::StrIEq(self)
+    }
+}
+
+impl IContainsArg for String {
+    fn to_op(self) -> Op {
+        Op::StrIContains(self)
+    }
+}
+
+impl IStartsWithArg for String {
+    fn to_op(self) -> Op {
+        Op::StrIStartsWith(self)
+    }
+}
+
+impl IEndsWithArg for String {
+    fn to_op(self) -> Op {
+        Op::StrIEndsWith(self)
+    }
+}
+
+impl MatchesArg for String {
+    fn to_op(self) -> Op {
+        Op::StrMatches(self)
+    }
+}
+
+impl IMatchesArg for String {
+    fn to_op(self) -> Op {
+        Op::StrIMatches(self)
+    }
+}
+
 impl InArg for Vec<String> {
     fn to_op(self) -> Op {
         Op::StrIn(self)
@@ -215,6 +374,18 @@ impl ContainsArg for &str {
     }
 }
 
+impl LikeArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrLike(self.to_string())
+    }
+}
+
+impl HasKeyArg for &str {
+    fn to_op(self) -> Op {
+        Op::JsonHasKey(self.to_string())
+    }
+}
+
 impl StartsWithArg for &str {
     fn to_op(self) -> Op {
         Op::StrStartsWith(self.to_string())
@@ -227,6 +398,42 @@ impl EndsWithArg for &str {
     }
 }
 
+impl IEqArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrIEq(self.to_string())
+    }
+}
+
+impl IContainsArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrIContains(self.to_string())
+    }
+}
+
+impl IStartsWithArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrIStartsWith(self.to_string())
+    }
+}
+
+impl IEndsWithArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrIEndsWith(self.to_string())
+    }
+}
+
+impl MatchesArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrMatches(self.to_string())
+    }
+}
+
+impl IMatchesArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrIMatches(self.to_string())
+    }
+}
+
 impl InArg for Vec<&str> {
     fn to_op(self) -> Op {
         Op::StrIn(self.iter().map(|s| s.to_string()).collect())
@@ -371,6 +578,84 @@ impl InArg for Vec<Uuid> {
     }
 }
 
+impl HasArg for String {
+    fn to_op(self) -> Op {
+        Op::StrArrayHas(self)
+    }
+}
+
+impl HasAllArg for Vec<String> {
+    fn to_op(self) -> Op {
+        Op::StrArrayHasAll(self)
+    }
+}
+
+impl OverlapsArg for Vec<String> {
+    fn to_op(self) -> Op {
+        Op::StrArrayOverlaps(self)
+    }
+}
+
+impl HasArg for i64 {
+    fn to_op(self) -> Op {
+        Op::IntArrayHas(self)
+    }
+}
+
+impl HasAllArg for Vec<i64> {
+    fn to_op(self) -> Op {
+        Op::IntArrayHasAll(self)
+    }
+}
+
+impl OverlapsArg for Vec<i64> {
+    fn to_op(self) -> Op {
+        Op::IntArrayOverlaps(self)
+    }
+}
+
+impl HasArg for Uuid {
+    fn to_op(self) -> Op {
+        Op::UuidArrayHas(self)
+    }
+}
+
+impl HasAllArg for Vec<Uuid> {
+    fn to_op(self) -> Op {
+        Op::UuidArrayHasAll(self)
+    }
+}
+
+impl OverlapsArg for Vec<Uuid> {
+    fn to_op(self) -> Op {
+        Op::UuidArrayOverlaps(self)
+    }
+}
+
+impl HasArg for &str {
+    fn to_op(self) -> Op {
+        Op::StrArrayHas(self.to_string())
+    }
+}
+
+impl HasAllArg for Vec<&str> {
+    fn to_op(self) -> Op {
+        Op::StrArrayHasAll(self.iter().map(|s| s.to_string()).collect())
+    }
+}
+
+impl OverlapsArg for Vec<&str> {
+    fn to_op(self) -> Op {
+        Op::StrArrayOverlaps(self.iter().map(|s| s.to_string()).collect())
+    }
+}
+
+impl JsonContainsArg for serde_json::Value {
+    fn to_op(self) -> Op {
+        Op::JsonContains(self)
+    }
+}
+
 impl Filter {
     /// Creates a filter to find entities whose field value is equal to a given one.
     pub fn eq(field: impl Into<String>, val: impl EqArg) -> Self {
@@ -449,6 +734,16 @@ impl Filter {
         }
     }
 
+    /// Creates a filter to find entities whose field value matches a LIKE pattern,
+    /// where `%` matches any sequence of characters, `_` matches any single character
+    /// and `\` escapes the next character.
+    pub fn like(field: impl Into<String>, val: impl LikeArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
     /// Creates a filter to find entities whose field value starts with a given value.
     pub fn starts_with(field: impl Into<String>, val: impl StartsWithArg) -> Self {
         Self::Value {
@@ -465,6 +760,111 @@ impl Filter {
         }
     }
 
+    /// Creates a filter to find entities whose field value is equal to a given value ignoring case.
+    pub fn ieq(field: impl Into<String>, val: impl IEqArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value has a given value ignoring case.
+    pub fn icontains(field: impl Into<String>, val: impl IContainsArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value starts with a given value ignoring case.
+    pub fn istarts_with(field: impl Into<String>, val: impl IStartsWithArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value ends with a given value ignoring case.
+    pub fn iends_with(field: impl Into<String>, val: impl IEndsWithArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value matches a given regular expression.
+    pub fn matches(field: impl Into<String>, val: impl MatchesArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value matches a given regular expression ignoring case.
+    pub fn imatches(field: impl Into<String>, val: impl IMatchesArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value is an array that has a given element.
+    pub fn has(field: impl Into<String>, val: impl HasArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value is an array that has all given elements.
+    pub fn has_all(field: impl Into<String>, val: impl HasAllArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose field value is an array that has any of given elements.
+    pub fn overlaps(field: impl Into<String>, val: impl OverlapsArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose JSON field value contains a given value
+    /// like the `@>` operator of PostgreSQL does: an object contains another object
+    /// if it has all its keys with contained values, and an array contains another array
+    /// if every element of the latter is contained in some element of the former.
+    pub fn json_contains(field: impl Into<String>, val: impl JsonContainsArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose JSON field value is an object
+    /// with a given key or an array with a given string.
+    pub fn has_key(field: impl Into<String>, val: impl HasKeyArg) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: val.to_op(),
+        }
+    }
+
+    /// Creates a filter to find entities whose JSON field value
+    /// has a given value at a given path of keys and array indices.
+    pub fn path_eq(
+        field: impl Into<String>,
+        path: &[&str],
+        val: impl Into<serde_json::Value>,
+    ) -> Self {
+        Self::Value {
+            field: field.into(),
+            op: Op::JsonPathEq(path.iter().map(|key| key.to_string()).collect(), val.into()),
+        }
+    }
+
     /// Creates a filter that joins multiple filters with the AND operator.
     pub fn and(filters: Vec<F>) -> Self {
         Self::And(filters)
@@ -476,9 +876,61 @@ impl Filter {
     }
 
     /// Creates a filter that adds the NOT operator to a wrapped filter.
+    #[allow(clippy::should_implement_trait)]
     pub fn not(filter: F) -> Self {
         Self::Not(Box::new(filter))
     }
+
+    /// Returns names of all the fields used in the filter.
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Self::And(filters) | Self::Or(filters) => {
+                filters.iter().flat_map(|filter| filter.fields()).collect()
+            }
+            Self::Not(filter) => filter.fields(),
+            Self::IsNone(field) => vec![field],
+            Self::Value { field, op } => match op.other_field() {
+                Some(other_field) => vec![field, other_field],
+                None => vec![field],
+            },
+        }
+    }
+}
+
+impl<S: Into<String>> EqArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldEq(self.0.into())
+    }
+}
+
+impl<S: Into<String>> NeArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldNe(self.0.into())
+    }
+}
+
+impl<S: Into<String>> LtArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldLt(self.0.into())
+    }
+}
+
+impl<S: Into<String>> GtArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldGt(self.0.into())
+    }
+}
+
+impl<S: Into<String>> LteArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldLte(self.0.into())
+    }
+}
+
+impl<S: Into<String>> GteArg for Field<S> {
+    fn to_op(self) -> Op {
+        Op::FieldGte(self.0.into())
+    }
 }
 
 /// Types of ordering.
@@ -490,6 +942,215 @@ pub enum Order {
     Desc(String),
 }
 
+/// Aggregate functions for the [Repo::aggregate](crate::Repo::aggregate) method.
+#[derive(Clone, Debug)]
+pub enum Agg {
+    /// Number of entities.
+    Count,
+    /// Sum of field values.
+    Sum(String),
+    /// Average of field values.
+    Avg(String),
+    /// Minimum of field values.
+    Min(String),
+    /// Maximum of field values.
+    Max(String),
+}
+
+impl Agg {
+    /// Creates a function that counts entities.
+    pub fn count() -> Self {
+        Self::Count
+    }
+
+    /// Creates a function that sums field values.
+    pub fn sum(field: impl Into<String>) -> Self {
+        Self::Sum(field.into())
+    }
+
+    /// Creates a function that averages field values.
+    pub fn avg(field: impl Into<String>) -> Self {
+        Self::Avg(field.into())
+    }
+
+    /// Creates a function that finds the minimum of field values.
+    pub fn min(field: impl Into<String>) -> Self {
+        Self::Min(field.into())
+    }
+
+    /// Creates a function that finds the maximum of field values.
+    pub fn max(field: impl Into<String>) -> Self {
+        Self::Max(field.into())
+    }
+
+    /// Returns the aggregated field, or `None` for [Agg::Count].
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            Self::Count => None,
+            Self::Sum(field) | Self::Avg(field) | Self::Min(field) | Self::Max(field) => {
+                Some(field)
+            }
+        }
+    }
+
+    /// Returns the name of a result field, such as `count` or `sum_money`.
+    pub fn alias(&self) -> String {
+        let (name, field) = match self {
+            Self::Count => return "count".to_string(),
+            Self::Sum(field) => ("sum", field),
+            Self::Avg(field) => ("avg", field),
+            Self::Min(field) => ("min", field),
+            Self::Max(field) => ("max", field),
+        };
+        format!("{}_{}", name, field)
+    }
+}
+
+/// Checks that an aggregation calculates something and doesn't use nested fields,
+/// since PostgreSQL doesn't know their types.
+pub(crate) fn check_aggregation(group_by: &[&str], aggs: &[Agg]) -> Result<(), Error> {
+    if group_by.is_empty() && aggs.is_empty() {
+        return Err(Error::InvalidQuery("Nothing to aggregate".to_string()));
+    }
+    let fields = group_by
+        .iter()
+        .copied()
+        .chain(aggs.iter().filter_map(Agg::field));
+    for field in fields {
+        if field.contains('.') {
+            return Err(Error::InvalidQuery(format!(
+                "Nested field {} can't be aggregated",
+                field
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Position of an entity in a sorted result
+/// that is used to retrieve entities following it.
+///
+/// It contains values of the [Query::order] fields of the entity.
+/// Cursors with null values are rejected with [Error::InvalidQuery],
+/// so the order fields of paginated queries must not be null.
+/// It can be converted to a string token and back
+/// with the `to_string` and `parse` methods.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor(pub Vec<serde_json::Value>);
+
+impl Cursor {
+    /// Creates a cursor from values of the order fields.
+    pub fn new(values: Vec<serde_json::Value>) -> Self {
+        Self(values)
+    }
+
+    /// Returns values of the order fields.
+    pub fn values(&self) -> &[serde_json::Value] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(&self.0).map_err(|_| fmt::Error)?;
+        for byte in json.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidQuery(format!("Invalid cursor {}", s));
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let values = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        Ok(Self(values))
+    }
+}
+
+/// Fields that can be used in filters and orders.
+/// All fields are allowed if a list is `None`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AllowedFields {
+    pub(crate) filterable: Option<Vec<String>>,
+    pub(crate) sortable: Option<Vec<String>>,
+}
+
+impl AllowedFields {
+    /// Checks that a filter uses only filterable fields.
+    pub(crate) fn check_filter(&self, filter: &F) -> Result<(), Error> {
+        if let Some(filterable) = &self.filterable {
+            for field in filter.fields() {
+                if !filterable.iter().any(|f| f == field) {
+                    return Err(Error::FieldNotAllowed(field.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that an order uses only sortable fields.
+    pub(crate) fn check_order(&self, order: &[Order]) -> Result<(), Error> {
+        if let Some(sortable) = &self.sortable {
+            for order_item in order {
+                let field = match order_item {
+                    Order::Asc(field) | Order::Desc(field) => field,
+                };
+                if !sortable.contains(field) {
+                    return Err(Error::FieldNotAllowed(field.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that fields selected by a projection or an aggregation
+    /// are filterable or sortable, since their values are returned to the caller.
+    /// All fields are allowed only if both lists are `None`.
+    pub(crate) fn check_fields<'f>(
+        &self,
+        fields: impl IntoIterator<Item = &'f str>,
+    ) -> Result<(), Error> {
+        if self.filterable.is_none() && self.sortable.is_none() {
+            return Ok(());
+        }
+        for field in fields {
+            let listed = |list: &Option<Vec<String>>| {
+                list.as_ref()
+                    .is_some_and(|list| list.iter().any(|f| f == field))
+            };
+            if !listed(&self.filterable) && !listed(&self.sortable) {
+                return Err(Error::FieldNotAllowed(field.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the filter and the order of a query.
+    pub(crate) fn check_query(&self, query: &Query) -> Result<(), Error> {
+        if let Some(filter) = &query.filter {
+            self.check_filter(filter)?;
+        }
+        if let Some(order) = &query.order {
+            self.check_order(order)?;
+        }
+        Ok(())
+    }
+}
+
+/// Order fields together with values of a [Cursor].
+pub(crate) type Keyset<'a> = (&'a [Order], &'a [serde_json::Value]);
+
 /// Struct for filtering entities with additional options.
 #[derive(Clone, Debug)]
 pub struct Query {
@@ -501,11 +1162,20 @@ pub struct Query {
     pub offset: Option<usize>,
     /// Order of entities before retrieval.
     pub order: Option<Vec<Order>>,
+    /// [Cursor] of an entity after which entities are retrieved.
+    /// It requires the `order` option.
+    pub after: Option<Cursor>,
 }
 
 /// Alias for the [Query] struct.
 pub type Q = Query;
 
+impl Default for Query {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Query {
     /// Creates a new `Query` with all options set to `None`.
     pub fn new() -> Self {
@@ -514,6 +1184,7 @@ impl Query {
             limit: None,
             offset: None,
             order: None,
+            after: None,
         }
     }
 
@@ -524,6 +1195,7 @@ impl Query {
             limit: None,
             offset: None,
             order: None,
+            after: None,
         }
     }
 
@@ -544,4 +1216,39 @@ impl Query {
         self.order = Some(order);
         self
     }
+
+    /// Sets the `after` option.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Returns the order fields together with the cursor values
+    /// if the `after` option is set.
+    pub(crate) fn keyset(&self) -> Result<Option<Keyset<'_>>, Error> {
+        let cursor = match &self.after {
+            Some(cursor) => cursor,
+            None => return Ok(None),
+        };
+
+        // Null values are sorted differently by different databases,
+        // so entities can't be reliably retrieved after them.
+        if cursor.values().iter().any(|value| value.is_null()) {
+            return Err(Error::InvalidQuery(
+                "A cursor can't contain null values".to_string(),
+            ));
+        }
+
+        match &self.order {
+            Some(order) if !order.is_empty() && order.len() == cursor.values().len() => {
+                Ok(Some((order, cursor.values())))
+            }
+            Some(_) => Err(Error::InvalidQuery(
+                "The cursor doesn't match the order".to_string(),
+            )),
+            None => Err(Error::InvalidQuery(
+                "The order is required to use a cursor".to_string(),
+            )),
+        }
+    }
 }
@@ -20,6 +20,7 @@ pub struct User {
 }
 
 impl User {
+    #[allow(clippy::redundant_field_names)]
     pub fn new(name: &str, age: i64) -> Self {
         Self {
             id: Uuid::new_v4(),
@@ -33,6 +34,40 @@ impl User {
     }
 }
 
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Document {
+    pub id: Uuid,
+    pub attrs: serde_json::Value,
+}
+
+impl Document {
+    pub fn new(attrs: serde_json::Value) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            attrs,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Post {
+    pub id: Uuid,
+    pub tags: Vec<String>,
+    pub scores: Vec<i64>,
+    pub authors: Vec<Uuid>,
+}
+
+impl Post {
+    pub fn new(tags: Vec<&str>, scores: Vec<i64>, authors: Vec<Uuid>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            scores,
+            authors,
+        }
+    }
+}
+
 pub async fn add_alice<'a, Db>(db: &Db, repo: &impl for<'b> Repo<User, Db<'a> = Db>) -> User {
     let mut u = User::new("Alice", 24);
     u.money = dec!(130.50);
@@ -1,10 +1,22 @@
 mod common;
 
-use orlok::base::{Db, Repo};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use orlok::base::{Db, IsolationLevel, Repo, RetryPolicy, TransactionOptions, Upserted};
 use orlok::json::{JsonDb, JsonRepo};
-use orlok::query::{Order, F, Q};
+use orlok::query::{Agg, Cursor, Field, Order, F, Q};
+use orlok::Error;
 
-use common::User;
+use common::{Document, Post, User};
 
 async fn users_repo() -> JsonRepo<User> {
     JsonRepo::new("users")
@@ -30,6 +42,23 @@ async fn get() {
     assert_eq!(result, bob);
 }
 
+#[tokio::test]
+async fn get_one() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+
+    let user = repo.get_one(&db, &F::eq("name", "Alice")).await.unwrap();
+    assert_eq!(user, alice);
+
+    let result = repo.get_one(&db, &F::eq("name", "Eve")).await;
+    assert!(matches!(result, Err(Error::NotFound)));
+
+    let result = repo.get_one(&db, &F::eq("is_evil", false)).await;
+    assert!(matches!(result, Err(Error::MultipleRows)));
+}
+
 #[tokio::test]
 async fn get_none() {
     let db = db().await;
@@ -40,6 +69,7 @@ async fn get_none() {
 }
 
 #[tokio::test]
+#[allow(clippy::bool_assert_comparison)]
 async fn exists() {
     let db = db().await;
     let repo = users_repo().await;
@@ -84,12 +114,14 @@ async fn delete() {
     common::add_alice(&db, &repo).await;
     common::add_bob(&db, &repo).await;
     let eve = common::add_eve(&db, &repo).await;
-    repo.delete(
-        &db,
-        &F::or(vec![F::eq("name", "Bob"), F::eq("name", "Alice")]),
-    )
-    .await
-    .unwrap();
+    let count = repo
+        .delete(
+            &db,
+            &F::or(vec![F::eq("name", "Bob"), F::eq("name", "Alice")]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(count, 2);
     let users = repo.get_many(&db, &Q::new()).await.unwrap();
     assert_eq!(users, vec![eve]);
 }
@@ -102,7 +134,10 @@ async fn update() {
     let mut bob = common::add_bob(&db, &repo).await;
     let eve = common::add_eve(&db, &repo).await;
     bob.name = "Robert".to_string();
-    repo.update(&db, &F::eq("id", bob.id), &bob).await.unwrap();
+    let count = repo.update(&db, &F::eq("id", bob.id), &bob).await.unwrap();
+    assert_eq!(count, 1);
+    let count = repo.update(&db, &F::eq("name", "Bob"), &bob).await.unwrap();
+    assert_eq!(count, 0);
     let users = repo
         .get_many(&db, &Q::new().order(vec![Order::Asc("name".to_string())]))
         .await
@@ -110,6 +145,592 @@ async fn update() {
     assert_eq!(users, vec![alice, eve, bob]);
 }
 
+#[tokio::test]
+async fn add_many() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let users: Vec<User> = (0..10000).map(|n| User::new("Alice", n)).collect();
+
+    repo.add_many(&db, &users).await.unwrap();
+
+    assert_eq!(repo.count_all(&db).await.unwrap(), 10000);
+    let user = repo.get(&db, &F::eq("age", 9999)).await.unwrap().unwrap();
+    assert_eq!(user.id, users[9999].id);
+}
+
+#[tokio::test]
+async fn upsert() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let mut alice = User::new("Alice", 24);
+
+    let result = repo.upsert(&db, &alice, &["id"]).await.unwrap();
+    assert_eq!(result, Upserted::Inserted);
+
+    alice.age = 25;
+    let result = repo.upsert(&db, &alice, &["id"]).await.unwrap();
+    assert_eq!(result, Upserted::Updated);
+
+    assert_eq!(repo.count_all(&db).await.unwrap(), 1);
+    let user = repo
+        .get(&db, &F::eq("id", alice.id))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.age, 25);
+}
+
+#[tokio::test]
+async fn upsert_with_null_conflict_target() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_eve(&db, &repo).await;
+
+    let result = repo
+        .upsert(&db, &User::new("Mallory", 20), &["weight"])
+        .await
+        .unwrap();
+
+    assert_eq!(result, Upserted::Inserted);
+    assert_eq!(repo.count_all(&db).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn upsert_without_conflict_target() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let result = repo.upsert(&db, &User::new("Bob", 30), &[]).await;
+
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+    let users = repo.get_many(&db, &Q::new()).await.unwrap();
+    let names: Vec<String> = users.into_iter().map(|user| user.name).collect();
+    assert_eq!(names, vec!["Alice"]);
+}
+
+#[tokio::test]
+async fn unknown_field() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let result = repo.get(&db, &F::eq("nickname", "Alice")).await;
+
+    assert!(matches!(result, Err(Error::UnknownField(field)) if field == "nickname"));
+}
+
+#[tokio::test]
+async fn type_mismatch() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let result = repo.get(&db, &F::eq("name", 24)).await;
+
+    assert!(matches!(result, Err(Error::TypeMismatch(_))));
+}
+
+#[tokio::test]
+async fn field_not_allowed() {
+    let db = db().await;
+    let repo = users_repo().await.filterable(&["name"]).sortable(&["age"]);
+    common::add_alice(&db, &repo).await;
+
+    let result = repo.get(&db, &F::eq("name", "Alice")).await;
+    assert!(result.unwrap().is_some());
+    let result = repo
+        .get(&db, &F::and(vec![F::eq("name", "Alice"), F::gt("age", 1)]))
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "age"));
+    let result = repo
+        .get_many(&db, &Q::new().order(vec![Order::Asc("name".to_string())]))
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "name"));
+    let result = repo
+        .project::<serde_json::Value>(&db, &Q::new(), &["name", "age"])
+        .await;
+    assert_eq!(result.unwrap().len(), 1);
+    let result = repo
+        .project::<serde_json::Value>(&db, &Q::new(), &["name", "money"])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+    let result = repo
+        .aggregate::<serde_json::Value>(&db, None, &["is_evil"], &[])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "is_evil"));
+    let result = repo
+        .aggregate::<serde_json::Value>(&db, None, &["name"], &[Agg::sum("money")])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+    let result = repo.delete(&db, &F::eq("age", 24)).await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(_))));
+    assert_eq!(repo.count_all(&db).await.unwrap(), 1);
+
+    // A list that isn't set doesn't allow selecting other fields.
+    let repo = users_repo().await.filterable(&["name"]);
+    let result = repo
+        .project::<serde_json::Value>(&db, &Q::new(), &["money"])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+    let result = repo
+        .aggregate::<serde_json::Value>(&db, None, &["money"], &[])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+    let repo = users_repo().await.sortable(&["age"]);
+    let result = repo
+        .project::<serde_json::Value>(&db, &Q::new(), &["age"])
+        .await;
+    assert_eq!(result.unwrap().len(), 1);
+    let result = repo
+        .project::<serde_json::Value>(&db, &Q::new(), &["money"])
+        .await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+}
+
+#[tokio::test]
+async fn like() {
+    let db = db().await;
+    let repo = users_repo().await;
+    for name in ["50% off", "50 off", "a_b", "axb", "back\\slash"] {
+        repo.add(&db, &User::new(name, 20)).await.unwrap();
+    }
+
+    let cases = vec![
+        (F::contains("name", "50%"), vec!["50% off"]),
+        (F::contains("name", "_"), vec!["a_b"]),
+        (F::starts_with("name", "a_"), vec!["a_b"]),
+        (F::ends_with("name", "\\slash"), vec!["back\\slash"]),
+        (F::like("name", "50%"), vec!["50% off", "50 off"]),
+        (F::like("name", "a_b"), vec!["a_b", "axb"]),
+        (F::like("name", "50\\%%"), vec!["50% off"]),
+        (F::like("name", "%\\\\%"), vec!["back\\slash"]),
+        (F::like("name", "_"), vec![]),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let names: Vec<String> = result.into_iter().map(|user| user.name).collect();
+        assert_eq!(names, expected);
+    }
+
+    let result = repo.get(&db, &F::like("name", "50\\")).await;
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+}
+
+#[tokio::test]
+async fn case_insensitive() {
+    let db = db().await;
+    let repo = users_repo().await;
+    for name in ["ALICE", "Bob", "Ωmega", "50% OFF", "Straße"] {
+        repo.add(&db, &User::new(name, 20)).await.unwrap();
+    }
+
+    let cases = vec![
+        (F::ieq("name", "alice"), vec!["ALICE"]),
+        (F::ieq("name", "ali"), vec![]),
+        (F::icontains("name", "O"), vec!["Bob", "50% OFF"]),
+        (F::icontains("name", "% off"), vec!["50% OFF"]),
+        (F::istarts_with("name", "ωM"), vec!["Ωmega"]),
+        (F::iends_with("name", "CE"), vec!["ALICE"]),
+        (F::ieq("name", "STRASSE"), vec!["Straße"]),
+        (F::icontains("name", "ss"), vec!["Straße"]),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let names: Vec<String> = result.into_iter().map(|user| user.name).collect();
+        assert_eq!(names, expected);
+    }
+}
+
+#[tokio::test]
+async fn matches() {
+    let db = db().await;
+    let repo = users_repo().await;
+    for name in ["AB-1234", "ab-12", "alice@example.com", "bob@"] {
+        repo.add(&db, &User::new(name, 20)).await.unwrap();
+    }
+
+    let cases = vec![
+        (F::matches("name", "^[A-Z]{2}-[0-9]{4}$"), vec!["AB-1234"]),
+        (
+            F::imatches("name", "^[a-z]{2}-[0-9]+$"),
+            vec!["AB-1234", "ab-12"],
+        ),
+        (
+            F::matches("name", "^[^@]+@[^@]+\\.[a-z]+$"),
+            vec!["alice@example.com"],
+        ),
+        (
+            F::not(F::matches("name", "^[^@]+@[^@]+\\.[a-z]+$")),
+            vec!["AB-1234", "ab-12", "bob@"],
+        ),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let names: Vec<String> = result.into_iter().map(|user| user.name).collect();
+        assert_eq!(names, expected);
+    }
+
+    let result = repo.get(&db, &F::matches("name", "(")).await;
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+    let filter = F::and(vec![F::eq("name", "Nobody"), F::matches("name", "(")]);
+    let result = repo.count(&db, &filter).await;
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+}
+
+#[tokio::test]
+async fn compare_fields() {
+    let db = db().await;
+    let repo = users_repo().await;
+    for (name, age, weight, money) in [
+        ("A", 50, Some(40.0), dec!(60)),
+        ("B", 30, Some(30.0), dec!(10)),
+        ("C", 20, None, dec!(20)),
+    ] {
+        let mut user = User::new(name, age);
+        user.weight = weight;
+        user.money = money;
+        repo.add(&db, &user).await.unwrap();
+    }
+
+    let cases = vec![
+        (F::gt("age", Field("weight")), vec!["A"]),
+        (F::eq("age", Field("weight")), vec!["B"]),
+        (F::lt("weight", Field("age".to_string())), vec!["A"]),
+        (F::gte("money", Field("age")), vec!["A", "C"]),
+        (F::ne("money", Field("age")), vec!["A", "B"]),
+        (F::lte("money", Field("money")), vec!["A", "B", "C"]),
+        (
+            F::eq("registered_at", Field("registered_at")),
+            vec!["A", "B", "C"],
+        ),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let names: Vec<String> = result.into_iter().map(|user| user.name).collect();
+        assert_eq!(names, expected);
+    }
+
+    let result = repo.get(&db, &F::gt("age", Field("height"))).await;
+    assert!(matches!(result, Err(Error::UnknownField(_))));
+    let repo = repo.filterable(&["age"]);
+    let result = repo.get(&db, &F::gt("age", Field("money"))).await;
+    assert!(matches!(result, Err(Error::FieldNotAllowed(field)) if field == "money"));
+}
+
+#[tokio::test]
+async fn compare_typed_fields() {
+    let db = db().await;
+    let repo: JsonRepo<serde_json::Value> = JsonRepo::new("orders");
+    for (name, price, budget, started_at, finished_at) in [
+        (
+            "A",
+            "9.50",
+            "10.00",
+            "2023-01-01T00:00:00Z",
+            "2023-01-01T00:00:00.5Z",
+        ),
+        (
+            "B",
+            "20.00",
+            "10.00",
+            "2023-01-01T00:00:01Z",
+            "2023-01-01T00:00:00.5Z",
+        ),
+        (
+            "C",
+            "10.0",
+            "10.00",
+            "2023-01-01T01:00:00+01:00",
+            "2023-01-01T00:00:00Z",
+        ),
+    ] {
+        let order = json!({
+            "name": name,
+            "price": price,
+            "budget": budget,
+            "started_at": started_at,
+            "finished_at": finished_at,
+        });
+        repo.add(&db, &order).await.unwrap();
+    }
+
+    let cases = vec![
+        (F::lt("price", Field("budget")), vec!["A"]),
+        (F::eq("price", Field("budget")), vec!["C"]),
+        (F::gt("price", Field("budget")), vec!["B"]),
+        (F::lt("started_at", Field("finished_at")), vec!["A"]),
+        (F::eq("started_at", Field("finished_at")), vec!["C"]),
+        (F::gt("started_at", Field("finished_at")), vec!["B"]),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let names: Vec<&str> = result.iter().map(|v| v["name"].as_str().unwrap()).collect();
+        assert_eq!(names, expected);
+    }
+}
+
+#[tokio::test]
+async fn compare_mixed_fields() {
+    let db = db().await;
+    let repo: JsonRepo<serde_json::Value> = JsonRepo::new("codes");
+    for (name, code, other) in [
+        ("A", "2", "10"),
+        ("B", "10", "1a"),
+        ("C", "1a", "2"),
+        ("D", "2023-01-01T00:00:00Z", "1"),
+    ] {
+        let code = json!({"name": name, "code": code, "other": other});
+        repo.add(&db, &code).await.unwrap();
+    }
+
+    let result = repo
+        .get_many(&db, &Q::filter(F::lt("code", Field("other"))))
+        .await
+        .unwrap();
+    let names: Vec<&str> = result.iter().map(|v| v["name"].as_str().unwrap()).collect();
+    assert_eq!(names, vec!["A", "B", "D"]);
+
+    // The result doesn't depend on the order of entities.
+    for codes in [["2", "10", "1a"], ["1a", "2", "10"], ["10", "1a", "2"]] {
+        let repo: JsonRepo<serde_json::Value> = JsonRepo::new("mixed_codes");
+        let db = JsonDb::new();
+        for code in codes {
+            repo.add(&db, &json!({ "code": code })).await.unwrap();
+        }
+        let rows: Vec<serde_json::Value> = repo
+            .aggregate(&db, None, &[], &[Agg::min("code"), Agg::max("code")])
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![json!({"min_code": "2", "max_code": "1a"})]);
+    }
+}
+
+#[tokio::test]
+async fn nested_fields() {
+    let db = db().await;
+    let repo: JsonRepo<serde_json::Value> = JsonRepo::new("users");
+    for (name, age, money) in [
+        ("Alice", 24, "130.50"),
+        ("Bob", 29, "150.06"),
+        ("Eve", 31, "230.25"),
+    ] {
+        let user = json!({
+            "name": name,
+            "age": age,
+            "profile": {"name": name, "stats": {"age": age, "money": money}},
+        });
+        repo.add(&db, &user).await.unwrap();
+    }
+
+    let cases = vec![
+        (Q::filter(F::eq("profile.name", "Bob")), vec!["Bob"]),
+        (
+            Q::filter(F::gt("profile.stats.age", 25)),
+            vec!["Bob", "Eve"],
+        ),
+        (
+            Q::filter(F::lt("profile.stats.money", dec!(200))),
+            vec!["Alice", "Bob"],
+        ),
+        (
+            Q::filter(F::is_none("profile.stats.height")),
+            vec!["Alice", "Bob", "Eve"],
+        ),
+        (
+            Q::filter(F::eq("profile.stats.age", Field("age"))),
+            vec!["Alice", "Bob", "Eve"],
+        ),
+        (
+            Q::new().order(vec![Order::Desc("profile.stats.age".to_string())]),
+            vec!["Eve", "Bob", "Alice"],
+        ),
+    ];
+    for (query, expected) in cases {
+        let result = repo.get_many(&db, &query).await.unwrap();
+        let names: Vec<&str> = result
+            .iter()
+            .map(|user| user["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    let query = Q::new()
+        .order(vec![Order::Desc("profile.stats.age".to_string())])
+        .limit(2);
+    let page = repo.get_page(&db, &query).await.unwrap();
+    let names: Vec<&str> = page
+        .items
+        .iter()
+        .map(|user| user["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["Eve", "Bob"]);
+    let page = repo
+        .get_page(&db, &query.after(page.next_cursor.unwrap()))
+        .await
+        .unwrap();
+    let names: Vec<&str> = page
+        .items
+        .iter()
+        .map(|user| user["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["Alice"]);
+}
+
+#[tokio::test]
+async fn missing_nested_key() {
+    let db = db().await;
+    let repo = JsonRepo::new("documents");
+    let documents = vec![
+        Document::new(json!({"city": "a"})),
+        Document::new(json!({"city": "b"})),
+        Document::new(json!({"country": "c"})),
+    ];
+    repo.add_many(&db, &documents).await.unwrap();
+
+    let cases = vec![
+        (F::eq("attrs.city", "a"), vec![0]),
+        (F::ne("attrs.city", "a"), vec![1]),
+        (F::is_none("attrs.city"), vec![2]),
+        (F::eq("attrs.city.name", "a"), vec![]),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let expected: Vec<Document> = expected.into_iter().map(|n| documents[n].clone()).collect();
+        assert_eq!(result, expected);
+    }
+
+    let result = repo.get_many(&db, &Q::filter(F::eq("city", "a"))).await;
+    assert!(matches!(result, Err(Error::UnknownField(field)) if field == "city"));
+}
+
+#[tokio::test]
+async fn json_filters() {
+    let db = db().await;
+    let repo = JsonRepo::new("documents");
+    let documents = vec![
+        Document::new(json!({"color": "red", "tags": ["new", "sale"], "size": {"w": 10, "h": 20}})),
+        Document::new(json!({"color": "blue", "tags": ["new"], "size": {"w": 10.0, "h": 5}})),
+        Document::new(
+            json!({"tags": [], "note": "it's \\ \"quoted\"\n", "big": 9007199254740993_i64}),
+        ),
+    ];
+    repo.add_many(&db, &documents).await.unwrap();
+
+    let cases = vec![
+        (F::json_contains("attrs", json!({"color": "red"})), vec![0]),
+        (
+            F::json_contains("attrs", json!({"tags": ["new"]})),
+            vec![0, 1],
+        ),
+        (
+            F::json_contains("attrs", json!({"size": {"w": 10}})),
+            vec![0, 1],
+        ),
+        (F::json_contains("attrs", json!({})), vec![0, 1, 2]),
+        (F::has_key("attrs", "color"), vec![0, 1]),
+        (F::has_key("attrs", "note"), vec![2]),
+        (F::path_eq("attrs", &["size", "h"], 5), vec![1]),
+        (F::path_eq("attrs", &["size", "w"], 10), vec![0, 1]),
+        (F::path_eq("attrs", &["big"], 9007199254740993_i64), vec![2]),
+        (F::path_eq("attrs", &["big"], 9007199254740992_i64), vec![]),
+        (
+            F::and(vec![
+                F::has_key("attrs", "color"),
+                F::has_key("attrs.color", "red"),
+            ]),
+            vec![0],
+        ),
+        (F::path_eq("attrs", &["tags", "1"], "sale"), vec![0]),
+        (
+            F::path_eq("attrs", &["note"], "it's \\ \"quoted\"\n"),
+            vec![2],
+        ),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let expected: Vec<Document> = expected.into_iter().map(|n| documents[n].clone()).collect();
+        assert_eq!(result, expected);
+    }
+}
+
+#[tokio::test]
+async fn array_filters() {
+    let db = db().await;
+    let repo = JsonRepo::new("posts");
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let posts = vec![
+        Post::new(vec!["rust", "sql"], vec![1, 2], vec![alice]),
+        Post::new(
+            vec!["rust", "quoted \"tag\", with \\ comma"],
+            vec![3],
+            vec![alice, bob],
+        ),
+        Post::new(vec![], vec![], vec![]),
+    ];
+    repo.add_many(&db, &posts).await.unwrap();
+
+    let cases = vec![
+        (F::has("tags", "rust"), vec![0, 1]),
+        (F::has("tags", "quoted \"tag\", with \\ comma"), vec![1]),
+        (F::has("scores", 2), vec![0]),
+        (F::has("authors", bob), vec![1]),
+        (F::has_all("tags", vec!["rust", "sql"]), vec![0]),
+        (F::has_all("scores", Vec::<i64>::new()), vec![0, 1, 2]),
+        (F::has_all("authors", vec![alice, bob]), vec![1]),
+        (F::overlaps("tags", vec!["sql", "go"]), vec![0]),
+        (F::overlaps("scores", vec![2, 3]), vec![0, 1]),
+        (F::overlaps("authors", vec![bob]), vec![1]),
+        (F::not(F::has("tags", "rust")), vec![2]),
+    ];
+    for (filter, expected) in cases {
+        let result = repo.get_many(&db, &Q::filter(filter)).await.unwrap();
+        let expected: Vec<Post> = expected.into_iter().map(|n| posts[n].clone()).collect();
+        assert_eq!(result, expected);
+    }
+}
+
+#[tokio::test]
+async fn update_many() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+
+    let user = User::new("Nobody", 40);
+    let count = repo
+        .update(&db, &F::eq("is_evil", false), &user)
+        .await
+        .unwrap();
+
+    assert_eq!(count, 2);
+    assert_eq!(repo.count(&db, &F::eq("name", "Nobody")).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn update_one() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    let mut bob = common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+
+    bob.name = "Robert".to_string();
+    repo.update_one(&db, &F::eq("name", "Bob"), &bob)
+        .await
+        .unwrap();
+    assert!(repo.exists(&db, &F::eq("name", "Robert")).await.unwrap());
+
+    let result = repo.update_one(&db, &F::eq("name", "Bob"), &bob).await;
+    assert!(matches!(result, Err(Error::NotFound)));
+
+    let user = User::new("Nobody", 40);
+    let result = repo.update_one(&db, &F::eq("is_evil", false), &user).await;
+    assert!(matches!(result, Err(Error::MultipleRows)));
+    assert!(!repo.exists(&db, &F::eq("name", "Nobody")).await.unwrap());
+}
+
 #[tokio::test]
 async fn get_many() {
     let db = db().await;
@@ -239,12 +860,505 @@ async fn get_many() {
 }
 
 #[tokio::test]
-async fn transaction() {
+async fn get_many_ordered_by_numeric_looking_text() {
     let db = db().await;
     let repo = users_repo().await;
-    common::add_bob(&db, &repo).await;
+    let mut names = Vec::new();
+    for i in 0..60 {
+        let name = match i % 3 {
+            0 => format!("{}x", i * 37 % 500),
+            _ => format!("{}", i * 53 % 500),
+        };
+        repo.add(&db, &User::new(&name, 20)).await.unwrap();
+        names.push(name);
+    }
+    names.sort();
 
-    db.transaction(|tx| {
+    let result = repo
+        .get_many(&db, &Q::new().order(vec![Order::Asc("name".to_string())]))
+        .await
+        .unwrap();
+    let result: Vec<String> = result.into_iter().map(|user| user.name).collect();
+    assert_eq!(result, names);
+
+    let result = repo
+        .get_many(&db, &Q::new().order(vec![Order::Desc("name".to_string())]))
+        .await
+        .unwrap();
+    let result: Vec<String> = result.into_iter().map(|user| user.name).collect();
+    names.reverse();
+    assert_eq!(result, names);
+}
+
+#[tokio::test]
+async fn get_page() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    let eve = common::add_eve(&db, &repo).await;
+    let query = Q::new()
+        .order(vec![
+            Order::Asc("name".to_string()),
+            Order::Asc("id".to_string()),
+        ])
+        .limit(2);
+
+    let page = repo.get_page(&db, &query).await.unwrap();
+    assert_eq!(page.items, vec![alice, bob]);
+    assert_eq!(page.total, 3);
+
+    let token = page.next_cursor.unwrap().to_string();
+    let cursor: Cursor = token.parse().unwrap();
+    let page = repo
+        .get_page(&db, &query.clone().after(cursor))
+        .await
+        .unwrap();
+    assert_eq!(page.items, vec![eve]);
+    assert_eq!(page.next_cursor, None);
+    assert_eq!(page.total, 3);
+
+    let cursor = Cursor::new(vec![json!("Zed"), json!(Uuid::nil())]);
+    let page = repo.get_page(&db, &query.after(cursor)).await.unwrap();
+    assert_eq!(page.items, vec![]);
+    assert_eq!(page.total, 3);
+}
+
+#[tokio::test]
+async fn get_page_mixed_order() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    let eve = common::add_eve(&db, &repo).await;
+    let query = Q::new()
+        .order(vec![
+            Order::Desc("is_evil".to_string()),
+            Order::Asc("registered_at".to_string()),
+        ])
+        .limit(1);
+
+    let mut users = Vec::new();
+    let mut page = repo.get_page(&db, &query).await.unwrap();
+    users.extend(page.items);
+    while let Some(cursor) = page.next_cursor {
+        page = repo
+            .get_page(&db, &query.clone().after(cursor))
+            .await
+            .unwrap();
+        users.extend(page.items);
+    }
+
+    assert_eq!(users, vec![eve, alice, bob]);
+}
+
+#[tokio::test]
+async fn get_page_nested_mixed_order() {
+    let db = db().await;
+    let repo: JsonRepo<serde_json::Value> = JsonRepo::new("users");
+    for (name, evil) in [("Alice", false), ("Bob", false), ("Eve", true)] {
+        let user = json!({"name": name, "profile": {"name": name, "evil": evil}});
+        repo.add(&db, &user).await.unwrap();
+    }
+    let query = Q::new()
+        .order(vec![
+            Order::Desc("profile.evil".to_string()),
+            Order::Asc("profile.name".to_string()),
+        ])
+        .limit(1);
+
+    let mut names = Vec::new();
+    let mut page = repo.get_page(&db, &query).await.unwrap();
+    names.extend(page.items.iter().map(|user| user["name"].clone()));
+    while let Some(cursor) = page.next_cursor {
+        page = repo
+            .get_page(&db, &query.clone().after(cursor))
+            .await
+            .unwrap();
+        names.extend(page.items.iter().map(|user| user["name"].clone()));
+    }
+
+    assert_eq!(names, vec!["Eve", "Alice", "Bob"]);
+}
+
+#[tokio::test]
+async fn get_many_after_cursor() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    let eve = common::add_eve(&db, &repo).await;
+    let query = Q::new()
+        .order(vec![Order::Asc("age".to_string())])
+        .after(Cursor::new(vec![json!(24)]));
+
+    let users = repo.get_many(&db, &query).await.unwrap();
+
+    assert_eq!(users, vec![bob, eve]);
+}
+
+#[tokio::test]
+async fn get_page_with_offset() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+    let query = Q::filter(F::eq("is_evil", false))
+        .order(vec![Order::Asc("name".to_string())])
+        .limit(1);
+
+    let page = repo.get_page(&db, &query.clone().offset(1)).await.unwrap();
+    assert_eq!(page.items, vec![bob]);
+    assert_eq!(page.total, 2);
+
+    let page = repo.get_page(&db, &query.clone().offset(5)).await.unwrap();
+    assert_eq!(page.items, vec![]);
+    assert_eq!(page.total, 2);
+}
+
+#[tokio::test]
+async fn get_page_with_max_limit() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    let query = Q::new()
+        .order(vec![Order::Asc("name".to_string())])
+        .limit(usize::MAX);
+
+    let page = repo.get_page(&db, &query).await.unwrap();
+
+    assert_eq!(page.items, vec![alice, bob]);
+    assert_eq!(page.next_cursor, None);
+    assert_eq!(page.total, 2);
+}
+
+#[tokio::test]
+async fn get_page_without_order() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+
+    let page = repo
+        .get_page(&db, &Q::filter(F::eq("name", "Alice")).limit(10))
+        .await
+        .unwrap();
+
+    assert_eq!(page.items, vec![alice]);
+    assert_eq!(page.next_cursor, None);
+    assert_eq!(page.total, 1);
+}
+
+#[tokio::test]
+async fn cursor_without_order() {
+    let db = db().await;
+    let repo = users_repo().await;
+
+    let query = Q::new().after(Cursor::new(vec![json!(24)]));
+    let result = repo.get_page(&db, &query).await;
+
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+}
+
+#[tokio::test]
+async fn cursor_with_null() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let query = Q::new()
+        .order(vec![Order::Asc("age".to_string())])
+        .after(Cursor::new(vec![json!(null)]));
+    let result = repo.get_page(&db, &query).await;
+
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct MoneyByEvil {
+    is_evil: bool,
+    count: i64,
+    sum_money: Decimal,
+    min_money: Decimal,
+    max_money: Decimal,
+    max_age: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct AgeStats {
+    count: i64,
+    avg_age: Decimal,
+    min_name: Option<String>,
+    sum_weight: Option<f64>,
+}
+
+#[tokio::test]
+async fn aggregate() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+    // Its money is the smallest, but it would be the largest if compared as text.
+    let mut mallory = User::new("Mallory", 20);
+    mallory.money = dec!(9.50);
+    repo.add(&db, &mallory).await.unwrap();
+
+    let rows: Vec<MoneyByEvil> = repo
+        .aggregate(
+            &db,
+            None,
+            &["is_evil"],
+            &[
+                Agg::count(),
+                Agg::sum("money"),
+                Agg::min("money"),
+                Agg::max("money"),
+                Agg::max("age"),
+            ],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        rows,
+        vec![
+            MoneyByEvil {
+                is_evil: false,
+                count: 3,
+                sum_money: dec!(290.06),
+                min_money: dec!(9.50),
+                max_money: dec!(150.06),
+                max_age: 29,
+            },
+            MoneyByEvil {
+                is_evil: true,
+                count: 1,
+                sum_money: dec!(230.25),
+                min_money: dec!(230.25),
+                max_money: dec!(230.25),
+                max_age: 31,
+            },
+        ]
+    );
+
+    let rows: Vec<serde_json::Value> = repo
+        .aggregate(&db, None, &["weight"], &[Agg::count()])
+        .await
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            json!({"weight": null, "count": 2}),
+            json!({"weight": 70.5, "count": 1}),
+            json!({"weight": 83.4, "count": 1}),
+        ]
+    );
+
+    let result: Result<Vec<MoneyByEvil>, Error> = repo
+        .aggregate(&db, None, &["profile.is_evil"], &[Agg::count()])
+        .await;
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+    let result: Result<Vec<MoneyByEvil>, Error> = repo
+        .aggregate(&db, None, &[], &[Agg::sum("profile.money")])
+        .await;
+    assert!(matches!(result, Err(Error::InvalidQuery(_))));
+}
+
+#[tokio::test]
+async fn aggregate_without_grouping() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+    let aggs = [
+        Agg::count(),
+        Agg::avg("age"),
+        Agg::min("name"),
+        Agg::sum("weight"),
+    ];
+
+    let rows: Vec<AgeStats> = repo
+        .aggregate(&db, Some(&F::ne("name", "Alice")), &[], &aggs)
+        .await
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![AgeStats {
+            count: 2,
+            avg_age: dec!(30),
+            min_name: Some("Bob".to_string()),
+            sum_weight: Some(83.4),
+        }]
+    );
+
+    // The average doesn't terminate, so it's rounded like a `numeric` one.
+    repo.add(&db, &User::new("Mallory", 27)).await.unwrap();
+    let rows: Vec<AgeStats> = repo
+        .aggregate(&db, Some(&F::ne("name", "Bob")), &[], &aggs)
+        .await
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![AgeStats {
+            count: 3,
+            avg_age: dec!(27.3333333333333333),
+            min_name: Some("Alice".to_string()),
+            sum_weight: Some(70.5),
+        }]
+    );
+
+    let result: Result<Vec<AgeStats>, Error> = repo
+        .aggregate(&db, Some(&F::eq("name", "Nobody")), &[], &aggs)
+        .await;
+    assert!(matches!(result, Err(Error::Decode(_))));
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct UserName {
+    id: Uuid,
+    name: String,
+}
+
+#[tokio::test]
+async fn project() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+    let query = Q::filter(F::eq("is_evil", false)).order(vec![Order::Asc("age".to_string())]);
+
+    let names: Vec<UserName> = repo.project(&db, &query, &["id", "name"]).await.unwrap();
+    assert_eq!(
+        names,
+        vec![
+            UserName {
+                id: alice.id,
+                name: alice.name
+            },
+            UserName {
+                id: bob.id,
+                name: bob.name
+            },
+        ]
+    );
+
+    let ages: Vec<serde_json::Value> = repo.project(&db, &query, &["age"]).await.unwrap();
+    assert_eq!(ages, vec![json!({"age": 24}), json!({"age": 29})]);
+
+    let documents_repo = JsonRepo::new("documents");
+    let documents = vec![
+        Document::new(json!({"city": "b"})),
+        Document::new(json!({"city": "a"})),
+    ];
+    documents_repo.add_many(&db, &documents).await.unwrap();
+    let query = Q::new().order(vec![Order::Asc("attrs.city".to_string())]);
+    let cities: Vec<serde_json::Value> = documents_repo
+        .project(&db, &query, &["attrs.city"])
+        .await
+        .unwrap();
+    assert_eq!(
+        cities,
+        vec![json!({"attrs.city": "a"}), json!({"attrs.city": "b"})]
+    );
+}
+
+#[tokio::test]
+async fn stream() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    let bob = common::add_bob(&db, &repo).await;
+    common::add_eve(&db, &repo).await;
+
+    let query = Q::filter(F::eq("is_evil", false)).order(vec![Order::Asc("name".to_string())]);
+    let users: Vec<User> = repo.stream(&db, &query).try_collect().await.unwrap();
+
+    assert_eq!(users, vec![alice, bob]);
+}
+
+#[tokio::test]
+async fn stream_in_transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+
+    let users = db
+        .transaction(|tx| {
+            Box::pin({
+                let repo = repo.clone();
+                async move {
+                    let bob = common::add_bob(tx, &repo).await;
+                    let mut users = Vec::new();
+                    let query = Q::new();
+                    let mut stream = repo.stream(tx, &query);
+                    while let Some(user) = stream.try_next().await? {
+                        users.push(user);
+                    }
+                    assert!(users.contains(&bob));
+                    Ok(users)
+                }
+            })
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(users.len(), 2);
+    assert!(users.contains(&alice));
+}
+
+#[tokio::test]
+async fn stream_error() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let result: Result<Vec<User>, Error> = repo
+        .stream(&db, &Q::filter(F::eq("nickname", "Alice")))
+        .try_collect()
+        .await;
+
+    assert!(matches!(result, Err(Error::UnknownField(_))));
+}
+
+#[tokio::test]
+async fn stream_lazily() {
+    let db = db().await;
+    let repo: JsonRepo<serde_json::Value> = JsonRepo::new("users");
+    repo.add(&db, &json!({"name": "Alice", "age": 24}))
+        .await
+        .unwrap();
+    repo.add(&db, &json!({"name": "Bob", "age": "old"}))
+        .await
+        .unwrap();
+    let query = Q::filter(F::gt("age", 20));
+
+    // The second item is matched only after the first entity is returned.
+    let mut stream = repo.stream(&db, &query);
+    let alice = stream.try_next().await.unwrap();
+    assert_eq!(alice, Some(json!({"name": "Alice", "age": 24})));
+    let result = stream.try_next().await;
+    assert!(matches!(result, Err(Error::TypeMismatch(_))));
+
+    let users: Vec<serde_json::Value> = repo
+        .stream(&db, &query.clone().limit(1))
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 1);
+}
+
+#[tokio::test]
+async fn transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_bob(&db, &repo).await;
+
+    db.transaction(|tx| {
         Box::pin({
             let repo = repo.clone();
             async move {
@@ -283,6 +1397,332 @@ async fn transaction_rollback() {
     assert!(alice.is_some());
 }
 
+#[tokio::test]
+async fn nested_transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    let alice = common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+    let eve = common::add_eve(&db, &repo).await;
+
+    db.transaction(|tx| {
+        Box::pin({
+            let repo = repo.clone();
+            async move {
+                repo.delete(tx, &F::eq("name", "Bob")).await?;
+
+                let result = tx
+                    .transaction::<_, ()>(|tx| {
+                        Box::pin({
+                            let repo = repo.clone();
+                            async move {
+                                repo.delete(tx, &F::eq("name", "Eve")).await?;
+                                anyhow::bail!("failed nested transaction")
+                            }
+                        })
+                    })
+                    .await;
+
+                assert!(result.is_err());
+                Ok(())
+            }
+        })
+    })
+    .await
+    .unwrap();
+
+    let users = repo
+        .get_many(&db, &Q::new().order(vec![Order::Asc("name".to_string())]))
+        .await
+        .unwrap();
+    assert_eq!(users, vec![alice, eve]);
+}
+
+#[tokio::test]
+async fn deeply_nested_transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+    let eve = common::add_eve(&db, &repo).await;
+
+    db.transaction(|tx| {
+        Box::pin({
+            let repo = repo.clone();
+            async move {
+                tx.transaction(|tx| {
+                    Box::pin({
+                        let repo = repo.clone();
+                        async move {
+                            repo.delete(tx, &F::eq("name", "Alice")).await?;
+
+                            tx.transaction(|tx| {
+                                Box::pin({
+                                    let repo = repo.clone();
+                                    async move {
+                                        repo.delete(tx, &F::eq("name", "Bob")).await?;
+                                        Ok(())
+                                    }
+                                })
+                            })
+                            .await
+                        }
+                    })
+                })
+                .await
+            }
+        })
+    })
+    .await
+    .unwrap();
+
+    let users = repo.get_many(&db, &Q::new()).await.unwrap();
+    assert_eq!(users, vec![eve]);
+}
+
+#[tokio::test]
+async fn nested_transaction_outer_rollback() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    common::add_bob(&db, &repo).await;
+
+    let result = db
+        .transaction::<_, ()>(|tx| {
+            Box::pin({
+                let repo = repo.clone();
+                async move {
+                    tx.transaction(|tx| {
+                        Box::pin({
+                            let repo = repo.clone();
+                            async move {
+                                repo.delete(tx, &F::eq("name", "Alice")).await?;
+                                Ok(())
+                            }
+                        })
+                    })
+                    .await?;
+                    anyhow::bail!("failed transaction")
+                }
+            })
+        })
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(repo.count_all(&db).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn transaction_isolation() {
+    let db = Arc::new(db().await);
+    let repo = users_repo().await;
+    common::add_alice(&*db, &repo).await;
+
+    db.transaction(|tx| {
+        Box::pin({
+            let repo = repo.clone();
+            let db = db.clone();
+            async move {
+                repo.delete(tx, &F::eq("name", "Alice")).await?;
+                assert!(!repo.exists(tx, &F::eq("name", "Alice")).await?);
+                assert!(repo.exists(&*db, &F::eq("name", "Alice")).await?);
+                Ok(())
+            }
+        })
+    })
+    .await
+    .unwrap();
+
+    assert!(!repo.exists(&*db, &F::eq("name", "Alice")).await.unwrap());
+}
+
+#[tokio::test]
+async fn transaction_rollback_keeps_concurrent_changes() {
+    let db = Arc::new(db().await);
+    let repo = users_repo().await;
+    common::add_alice(&*db, &repo).await;
+
+    let result = db
+        .transaction::<_, ()>(|tx| {
+            Box::pin({
+                let repo = repo.clone();
+                let db = db.clone();
+                async move {
+                    repo.delete(tx, &F::eq("name", "Alice")).await?;
+                    common::add_bob(&*db, &repo).await;
+                    anyhow::bail!("failed transaction")
+                }
+            })
+        })
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(repo.count_all(&*db).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn transaction_conflict() {
+    let db = Arc::new(db().await);
+    let repo = users_repo().await;
+    common::add_alice(&*db, &repo).await;
+
+    let result = db
+        .transaction(|tx| {
+            Box::pin({
+                let repo = repo.clone();
+                let db = db.clone();
+                async move {
+                    repo.delete(tx, &F::eq("name", "Alice")).await?;
+                    common::add_bob(&*db, &repo).await;
+                    Ok(())
+                }
+            })
+        })
+        .await;
+
+    assert!(result.is_err());
+    let users = repo.get_many(&*db, &Q::new()).await.unwrap();
+    let names: Vec<&str> = users.iter().map(|u| u.name.as_str()).collect();
+    assert_eq!(names, vec!["Alice", "Bob"]);
+}
+
+#[tokio::test]
+async fn transaction_with_options() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+    let options = TransactionOptions::new()
+        .isolation_level(IsolationLevel::Serializable)
+        .read_only()
+        .deferrable();
+
+    let count = db
+        .transaction_with(options, |tx| {
+            Box::pin({
+                let repo = repo.clone();
+                async move { Ok(repo.count_all(tx).await?) }
+            })
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn read_only_transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    let result = db
+        .transaction_with(TransactionOptions::new().read_only(), |tx| {
+            Box::pin({
+                let repo = repo.clone();
+                async move { Ok(repo.delete(tx, &F::eq("name", "Alice")).await?) }
+            })
+        })
+        .await;
+
+    assert!(matches!(
+        result.unwrap_err().downcast_ref::<Error>(),
+        Some(Error::ReadOnly(_))
+    ));
+    assert_eq!(repo.count_all(&db).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn read_only_nested_transaction() {
+    let db = db().await;
+    let repo = users_repo().await;
+    common::add_alice(&db, &repo).await;
+
+    db.transaction(|tx| {
+        Box::pin({
+            let repo = repo.clone();
+            async move {
+                let result = tx
+                    .transaction_with(TransactionOptions::new().read_only(), |tx| {
+                        Box::pin({
+                            let repo = repo.clone();
+                            async move { Ok(repo.delete(tx, &F::eq("name", "Alice")).await?) }
+                        })
+                    })
+                    .await;
+                assert!(result.is_err());
+                common::add_bob(tx, &repo).await;
+                Ok(())
+            }
+        })
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(repo.count_all(&db).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn transaction_with_retry() {
+    let db = Arc::new(db().await);
+    let repo = users_repo().await;
+    common::add_alice(&*db, &repo).await;
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    db.transaction_with_retry(TransactionOptions::new(), RetryPolicy::new(), |tx| {
+        Box::pin({
+            let repo = repo.clone();
+            let db = db.clone();
+            let attempts = attempts.clone();
+            async move {
+                repo.delete(tx, &F::eq("name", "Alice")).await?;
+                if attempts.fetch_add(1, SeqCst) == 0 {
+                    common::add_bob(&*db, &repo).await;
+                }
+                Ok(())
+            }
+        })
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(attempts.load(SeqCst), 2);
+    let users = repo.get_many(&*db, &Q::new()).await.unwrap();
+    let names: Vec<&str> = users.iter().map(|u| u.name.as_str()).collect();
+    assert_eq!(names, vec!["Bob"]);
+}
+
+#[tokio::test]
+async fn transaction_with_retry_exhausted() {
+    let db = Arc::new(db().await);
+    let repo = users_repo().await;
+    common::add_alice(&*db, &repo).await;
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    let result = db
+        .transaction_with_retry(
+            TransactionOptions::new(),
+            RetryPolicy::new().max_retries(1),
+            |tx| {
+                Box::pin({
+                    let repo = repo.clone();
+                    let db = db.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        attempts.fetch_add(1, SeqCst);
+                        repo.delete(tx, &F::eq("name", "Alice")).await?;
+                        common::add_bob(&*db, &repo).await;
+                        Ok(())
+                    }
+                })
+            },
+        )
+        .await;
+
+    assert!(result.is_err());
+    assert!(JsonDb::is_retryable(&result.unwrap_err()));
+    assert_eq!(attempts.load(SeqCst), 2);
+}
+
 #[tokio::test]
 async fn get_for_update() {
     let db = db().await;
@@ -125,3 +125,21 @@ async fn test_one_to_many() {
         .unwrap();
     assert_eq!(a, alice);
 }
+
+#[tokio::test]
+async fn test_update_without_rows() {
+    let db = db().await;
+    let repo = users_repo();
+    let bob = User::new("Bob", vec!["bob@test.com".to_string()]);
+    repo.add(&db, &bob).await.unwrap();
+    let nobody = User::new("Nobody", vec!["nobody@test.com".to_string()]);
+
+    let count = repo
+        .update(&db, &F::eq("id", nobody.id), &nobody)
+        .await
+        .unwrap();
+
+    assert_eq!(count, 0);
+    let b = repo.get(&db, &F::eq("id", bob.id)).await.unwrap().unwrap();
+    assert_eq!(b, bob);
+}